@@ -1,36 +1,81 @@
-use candid::{candid_method, CandidType, Decode, Deserialize, Encode, Principal};
+use candid::{candid_method, CandidType, Decode, Deserialize, Encode, Nat, Principal};
 use ic_canister_log::{declare_log_buffer, log};
 use ic_canister_serve::{serve_logs, serve_metrics};
 use ic_cdk::api::management_canister::http_request::{
     http_request as make_http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod,
     HttpResponse, TransformArgs, TransformContext,
 };
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(not(any(target_arch = "wasm32", test)))]
 use ic_stable_structures::file_mem::FileMemory;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-#[cfg(target_arch = "wasm32")]
+#[cfg(any(target_arch = "wasm32", test))]
 use ic_stable_structures::DefaultMemoryImpl;
 use ic_stable_structures::{BoundedStorable, Cell, StableBTreeMap, Storable};
 #[macro_use]
 extern crate num_derive;
+use num_traits::ToPrimitive;
+use serde::Serialize;
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::hash_set::HashSet;
 use std::collections::HashMap;
-#[cfg(not(target_arch = "wasm32"))]
+use std::collections::VecDeque;
+#[cfg(not(any(target_arch = "wasm32", test)))]
 use std::fs::File;
 
 const INGRESS_OVERHEAD_BYTES: u128 = 100;
-const INGRESS_MESSAGE_RECEIVED_COST: u128 = 1_200_000u128;
-const INGRESS_MESSAGE_BYTE_RECEIVED_COST: u128 = 2_000u128;
-const HTTP_OUTCALL_REQUEST_COST: u128 = 400_000_000u128;
-const HTTP_OUTCALL_BYTE_RECEIEVED_COST: u128 = 100_000u128;
+// Default pricing, see Pricing.
+const SUBNET_SIZE: u64 = 13;
+const INGRESS_MESSAGE_RECEIVED_COST: u64 = 1_200_000;
+const INGRESS_MESSAGE_BYTE_RECEIVED_COST: u64 = 2_000;
+const HTTP_OUTCALL_REQUEST_BASE_COST: u64 = 3_000_000;
+const HTTP_OUTCALL_REQUEST_PER_NODE_COST: u64 = 60_000;
+const HTTP_OUTCALL_REQUEST_BYTE_COST: u64 = 400;
+const HTTP_OUTCALL_RESPONSE_BYTE_COST: u64 = 800;
+// Ingress costs are given for a subnet of this size and scale linearly with the subnet size.
+const INGRESS_REFERENCE_SUBNET_SIZE: u128 = 13;
 
 const MINIMUM_WITHDRAWAL_CYCLES: u128 = 1_000_000_000u128;
 
 const STRING_STORABLE_MAX_SIZE: u32 = 100;
+const PROVIDER_ORDER_MAX_LEN: usize = 32;
+const USAGE_HISTORY_MAX_LEN: u64 = 100;
+const GET_PROVIDERS_MAX_LIMIT: u32 = 100;
+const PROVIDER_MAX_HEADERS: usize = 8;
+// Version byte followed by the Candid encoded Provider.
+const PROVIDER_STORAGE_VERSION: u8 = 1;
+const PROVIDER_MAX_SIZE: u32 = 4096;
+const LEGACY_PROVIDER_MAX_SIZE: u32 = 256;
+// With max_response_bytes = 0 the limit is the percentile of recent (raw) response sizes plus a
+// margin, or the default if there are no recent responses. Sizes are kept for a limited number
+// of methods (of limited length), providers and hosts.
+const AUTO_RESPONSE_SIZE_SAMPLES: usize = 100;
+const AUTO_RESPONSE_SIZE_PERCENTILE: usize = 95;
+const AUTO_RESPONSE_SIZE_MAX_KEYS: usize = 1_000;
+const AUTO_RESPONSE_SIZE_MAX_METHOD_LEN: usize = 64;
+const AUTO_MAX_RESPONSE_BYTES_DEFAULT: u64 = 8_192;
+const MAX_RESPONSE_BYTES: u64 = 2_000_000;
+// The outcall is charged for the raw response, which is larger than the transformed response as
+// the headers are stripped and the JSON is canonicalized and fields are dropped. The raw size is
+// bounded by the transformed size times the factor plus an allowance for the headers.
+const RAW_RESPONSE_SIZE_FACTOR: u64 = 2;
+const RAW_RESPONSE_HEADER_BYTES: u64 = 1_024;
+// Number of blocks per eth_getLogs request and the maximum number of initial requests.
+const ETH_GET_LOGS_CHUNK_BLOCKS: u64 = 2_000;
+const ETH_GET_LOGS_MAX_CHUNKS: u64 = 64;
 const WASM_PAGE_SIZE: u64 = 65536;
+// Response fields which differ between nodes of a provider, by JSON RPC method.
+// Paths are relative to the response and are applied to each element of arrays.
+const TRANSFORM_PRESETS: &[(&str, &[&str])] = &[
+    ("eth_getBlockByHash", &["result.totalDifficulty"]),
+    ("eth_getBlockByNumber", &["result.totalDifficulty"]),
+    ("eth_getLogs", &["result.blockTimestamp"]),
+    ("eth_getTransactionByHash", &["result.yParity"]),
+    ("eth_getTransactionReceipt", &["result.logs.blockTimestamp"]),
+];
 
+// Seeds the stable SERVICE_HOSTS_ALLOWLIST on first install. After that the allowlist
+// is managed at runtime via add_service_host() and remove_service_host().
 const INITIAL_SERVICE_HOSTS_ALLOWLIST: &[&str] = &[
     "cloudflare-eth.com",
     "ethereum.publicnode.com",
@@ -58,24 +103,13 @@ const INITIAL_SERVICE_HOSTS_ALLOWLIST: &[&str] = &[
     "eth-mainnet.gateway.pokt.network",
 ];
 
-// Static permissions. The canister creator is also authorized for all permissions.
-
-// Principals allowed to send JSON RPCs.
-const OPEN_RPC_ACCESS: bool = true;
-const RPC_ALLOWLIST: &[&str] = &[];
-// Principals allowed to registry API keys.
-const REGISTER_PROVIDER_ALLOWLIST: &[&str] = &[];
-// Principals that will not be charged cycles to send JSON RPCs.
-const FREE_RPC_ALLOWLIST: &[&str] = &[];
-// Principals who have Admin authorization.
-const AUTHORIZED_ADMIN: &[&str] = &[];
-
-type AllowlistSet = HashSet<&'static &'static str>;
+// Who may send JSON RPCs unless configured otherwise, see InitArgs.
+const DEFAULT_RPC_ACCESS: RpcAccess = RpcAccess::Open;
 
 #[allow(unused)] // Some compiler quirk causes this to be reported as unused.
-#[cfg(not(target_arch = "wasm32"))]
+#[cfg(not(any(target_arch = "wasm32", test)))]
 type Memory = VirtualMemory<FileMemory>;
-#[cfg(target_arch = "wasm32")]
+#[cfg(any(target_arch = "wasm32", test))]
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 declare_log_buffer!(name = INFO, capacity = 1000);
@@ -89,20 +123,169 @@ struct Metrics {
     json_rpc_request_err_no_permission: u64,
     json_rpc_request_err_service_url_host_not_allowed: u64,
     json_rpc_request_err_http_request_error: u64,
+    json_rpc_request_err_http_status: u64,
+    json_rpc_request_err_json_rpc_error: u64,
     json_rpc_host_requests: HashMap<String, u64>,
+    json_rpc_multi_requests: u64,
+    json_rpc_multi_request_err_inconsistent: u64,
+    json_rpc_chain_requests: u64,
+    json_rpc_chain_request_failovers: u64,
+    eth_get_logs_requests: u64,
+    eth_get_logs_range_splits: u64,
+    account_cycles_deposited: u128,
+    account_cycles_withdrawn: u128,
+    account_cycles_debited: u128,
+    account_cycles_refunded: u128,
+    json_rpc_request_err_http_request_error_cycles_refunded: u128,
+    json_rpc_request_response_bytes_reserved: u64,
+    json_rpc_request_response_bytes_received: u64,
+    json_rpc_request_response_bytes_cycles_refunded: u128,
+    json_rpc_request_auto_max_response_bytes_retries: u64,
 }
 
+#[derive(PartialEq, Eq, Hash)]
+struct ResponseSizeKey {
+    method: String,
+    provider_id: Option<u64>,
+    host: String,
+}
+
+// Each Auth is a distinct bit of the permissions stored for a principal.
 #[derive(Clone, Debug, PartialEq, CandidType, FromPrimitive, Deserialize)]
 enum Auth {
     Admin = 1,
     Rpc = 2,
-    RegisterProvider = 3,
-    FreeRpc = 4,
+    RegisterProvider = 4,
+    FreeRpc = 8,
+}
+
+// Permission bits stored before each Auth had a distinct bit, see migrate_auth.
+const LEGACY_AUTH_ADMIN: u32 = 1;
+const LEGACY_AUTH_RPC: u32 = 2;
+const LEGACY_AUTH_FREE_RPC: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+enum RpcAccess {
+    // Any principal may send JSON RPCs.
+    Open,
+    // Only principals authorized for Rpc may send JSON RPCs.
+    Allowlist,
+    // Any principal not in RPC_DENYLIST may send JSON RPCs.
+    Denylist,
+}
+
+// Deployment configuration. Fields which are not set keep their current (or default) value.
+// Setting a list of principals replaces the principals with that authorization.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct InitArgs {
+    rpc_access: Option<RpcAccess>,
+    // Principals allowed to send JSON RPCs with RpcAccess::Allowlist.
+    rpc_allowlist: Option<Vec<Principal>>,
+    // Principals not allowed to send JSON RPCs with RpcAccess::Denylist.
+    rpc_denylist: Option<Vec<Principal>>,
+    // Principals allowed to register API keys.
+    register_provider_allowlist: Option<Vec<Principal>>,
+    // Principals that will not be charged cycles to send JSON RPCs.
+    free_rpc_allowlist: Option<Vec<Principal>>,
+    // Principals who have Admin authorization in addition to the controllers.
+    admins: Option<Vec<Principal>>,
+    // Replaces the service hosts allowlist.
+    service_hosts: Option<Vec<ServiceHost>>,
+    pricing: Option<Pricing>,
+}
+
+// The upgrade arguments have the same form as InitArgs.
+type UpgradeArgs = InitArgs;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Config {
+    rpc_access: RpcAccess,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rpc_access: DEFAULT_RPC_ACCESS,
+        }
+    }
+}
+
+// The deployment configuration as a JSON5 document, see import_config and export_config.
+// Principals and service hosts are in their textual form and are validated when parsed so
+// that errors are reported with their location.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigBundle {
+    rpc_access: Option<RpcAccess>,
+    rpc_allowlist: Option<Vec<PrincipalText>>,
+    rpc_denylist: Option<Vec<PrincipalText>>,
+    register_provider_allowlist: Option<Vec<PrincipalText>>,
+    free_rpc_allowlist: Option<Vec<PrincipalText>>,
+    admins: Option<Vec<PrincipalText>>,
+    service_hosts: Option<Vec<ServiceHostText>>,
+    pricing: Option<Pricing>,
+    providers: Option<Vec<ProviderConfig>>,
+}
+
+// A provider without the api_key and headers, which may contain secrets.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProviderConfig {
+    provider_id: u64,
+    // The owner and chain_id can not be changed.
+    owner: PrincipalText,
+    chain_id: u64,
+    service_url: String,
+    cycles_per_call: u64,
+    cycles_per_message_byte: u64,
+    active: bool,
+    maintenance: Option<MaintenanceWindow>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+struct PrincipalText(Principal);
+
+impl TryFrom<String> for PrincipalText {
+    type Error = String;
+    fn try_from(text: String) -> Result<Self, String> {
+        match Principal::from_text(&text) {
+            Ok(principal) => Ok(Self(principal)),
+            Err(e) => Err(format!("invalid principal {}: {}", text, e)),
+        }
+    }
+}
+
+impl From<PrincipalText> for String {
+    fn from(principal: PrincipalText) -> Self {
+        principal.0.to_text()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+struct ServiceHostText(ServiceHost);
+
+impl TryFrom<String> for ServiceHostText {
+    type Error = String;
+    fn try_from(text: String) -> Result<Self, String> {
+        let service_host = ServiceHost::parse(&text)?;
+        service_host.key()?;
+        Ok(Self(service_host))
+    }
+}
+
+impl From<ServiceHostText> for String {
+    fn from(service_host: ServiceHostText) -> Self {
+        service_host.0.to_string()
+    }
 }
 
 #[derive(Clone, Debug, Default, CandidType, Deserialize)]
 struct Metadata {
     next_provider_id: u64,
+    // Optional so that Metadata written by earlier versions still decodes.
+    service_hosts_initialized: Option<bool>,
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -150,6 +333,40 @@ struct RegisteredProvider {
     service_url: String,
     cycles_per_call: u64,
     cycles_per_message_byte: u64,
+    status: ProviderStatus,
+    maintenance: Option<MaintenanceWindow>,
+}
+
+// Fields which are None match any provider.
+#[derive(Debug, Default, CandidType, Deserialize)]
+struct ProviderFilter {
+    chain_id: Option<u64>,
+    owner: Option<Principal>,
+    // The host of the service_url.
+    host: Option<String>,
+    // Whether the status is Active.
+    active: Option<bool>,
+}
+
+#[derive(Debug, CandidType)]
+struct ProviderPage {
+    providers: Vec<RegisteredProvider>,
+    // The cursor for the next page, None if this is the last page.
+    next_cursor: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+enum ProviderStatus {
+    Active,
+    Disabled,
+    Maintenance,
+}
+
+// Times are in nanoseconds since the epoch, from start (inclusive) to end (exclusive).
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+struct MaintenanceWindow {
+    start: u64,
+    end: u64,
 }
 
 #[derive(Debug, CandidType, Deserialize)]
@@ -159,6 +376,25 @@ struct RegisterProvider {
     api_key: String,
     cycles_per_call: u64,
     cycles_per_message_byte: u64,
+    headers: Option<Vec<HeaderTemplate>>,
+}
+
+// A header added to requests using a provider. "{api_key}" in the value is replaced by the
+// api_key, which is then not appended to the service_url.
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+struct HeaderTemplate {
+    name: String,
+    value: String,
+}
+
+// Fields which are None are not changed.
+#[derive(Debug, CandidType, Deserialize)]
+struct UpdateProvider {
+    service_url: Option<String>,
+    api_key: Option<String>,
+    cycles_per_call: Option<u64>,
+    cycles_per_message_byte: Option<u64>,
+    headers: Option<Vec<HeaderTemplate>>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -171,401 +407,2887 @@ struct Provider {
     cycles_per_call: u64,
     cycles_per_message_byte: u64,
     cycles_owed: u128,
+    // Optional so that Providers written by earlier versions still decode; None is active.
+    active: Option<bool>,
+    maintenance: Option<MaintenanceWindow>,
+    headers: Option<Vec<HeaderTemplate>>,
 }
 
-impl Storable for Metadata {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+impl Provider {
+    fn api_key_in_headers(&self) -> bool {
+        self.headers
+            .iter()
+            .flatten()
+            .any(|header| header.value.contains("{api_key}"))
     }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(&bytes, Self).unwrap()
+
+    /// The URL of requests using the provider.
+    fn url(&self) -> String {
+        if self.api_key_in_headers() {
+            self.service_url.clone()
+        } else {
+            self.service_url.clone() + &self.api_key
+        }
     }
-}
 
-impl Storable for Provider {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
+    /// The headers of requests using the provider, with the api_key filled in.
+    fn request_headers(&self) -> Vec<HttpHeader> {
+        self.headers
+            .iter()
+            .flatten()
+            .map(|header| HttpHeader {
+                name: header.name.clone(),
+                value: header.value.replace("{api_key}", &self.api_key),
+            })
+            .collect()
     }
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(&bytes, Self).unwrap()
+
+    /// The bytes the request headers add to the size of requests using the provider.
+    fn request_header_bytes(&self) -> u64 {
+        self.request_headers()
+            .iter()
+            .map(|header| (header.name.len() + header.value.len()) as u64)
+            .sum()
     }
-}
 
-impl BoundedStorable for Provider {
-    const MAX_SIZE: u32 = 256; // A reasonable limit.
-    const IS_FIXED_SIZE: bool = false;
+    fn validate(&self) -> Result<(), EthRpcError> {
+        self.validate_with(None)
+    }
+
+    /// Validates the provider against the given service hosts allowlist instead of the current
+    /// one, if any.
+    fn validate_with(&self, service_hosts: Option<&[ServiceHost]>) -> Result<(), EthRpcError> {
+        check_service_url(&self.url(), service_hosts)?;
+        let invalid = |message: String| Err(EthRpcError::InvalidProvider(message));
+        let headers = self.headers.as_deref().unwrap_or_default();
+        if headers.len() > PROVIDER_MAX_HEADERS {
+            return invalid(format!("at most {} headers", PROVIDER_MAX_HEADERS));
+        }
+        for header in headers {
+            let name = header.name.to_lowercase();
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return invalid(format!("invalid header name {}", header.name));
+            }
+            if name == "host" || name == "content-type" {
+                return invalid(format!("header {} can not be set", header.name));
+            }
+            if header.value.contains(['\r', '\n']) {
+                return invalid(format!("invalid header value for {}", header.name));
+            }
+        }
+        let size = self.to_bytes().len();
+        if size > PROVIDER_MAX_SIZE as usize {
+            return invalid(format!(
+                "encoded size {} exceeds {} bytes",
+                size, PROVIDER_MAX_SIZE
+            ));
+        }
+        Ok(())
+    }
+
+    fn status(&self, time: u64) -> ProviderStatus {
+        if self.active == Some(false) {
+            return ProviderStatus::Disabled;
+        }
+        match &self.maintenance {
+            Some(window) if window.start <= time && time < window.end => {
+                ProviderStatus::Maintenance
+            }
+            _ => ProviderStatus::Active,
+        }
+    }
 }
 
-thread_local! {
-    // Transient static data: this is reset when the canister is upgraded.
-    static METRICS: RefCell<Metrics> = RefCell::new(Metrics::default());
-    static SERVICE_HOSTS_ALLOWLIST: RefCell<AllowlistSet> = RefCell::new(AllowlistSet::new());
-    static AUTH_STABLE: RefCell<HashSet<Principal>> = RefCell::new(HashSet::<Principal>::new());
+// The fees of the subnet the canister is deployed on, in cycles.
+#[derive(Clone, Debug, PartialEq, CandidType, Serialize, Deserialize)]
+struct Pricing {
+    // Number of nodes in the subnet.
+    subnet_size: u64,
+    // Per ingress message and byte on a 13 node subnet.
+    ingress_message_received_cost: u64,
+    ingress_message_byte_received_cost: u64,
+    // The HTTPS outcall cost is
+    // (base + per_node * subnet_size) * subnet_size
+    //   + request_byte * subnet_size * request_bytes
+    //   + response_byte * subnet_size * max_response_bytes.
+    http_outcall_request_base_cost: u64,
+    http_outcall_request_per_node_cost: u64,
+    http_outcall_request_byte_cost: u64,
+    http_outcall_response_byte_cost: u64,
+}
 
-    // Stable static data: this is preserved when the canister is upgraded.
-    #[cfg(not(target_arch = "wasm32"))]
-    static MEMORY_MANAGER: RefCell<MemoryManager<FileMemory>> =
-        RefCell::new(MemoryManager::init(FileMemory::new(File::open("stable_memory.bin").unwrap())));
-    #[cfg(target_arch = "wasm32")]
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
-    static METADATA: RefCell<Cell<Metadata, Memory>> = RefCell::new(Cell::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
-            <Metadata>::default()).unwrap());
-    static AUTH: RefCell<StableBTreeMap<PrincipalStorable, u32, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))));
-    static PROVIDERS: RefCell<StableBTreeMap<u64, Provider, Memory>> = RefCell::new(
-        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))));
+impl Pricing {
+    fn validate(&self) -> Result<(), String> {
+        if self.subnet_size == 0 {
+            return Err("subnet_size must be positive".to_string());
+        }
+        Ok(())
+    }
 }
 
-#[derive(CandidType, Debug)]
-enum EthRpcError {
-    NoPermission,
-    TooFewCycles(String),
-    ServiceUrlParseError,
-    ServiceUrlHostMissing,
-    ServiceUrlHostNotAllowed,
-    ProviderNotFound,
-    HttpRequestError { code: u32, message: String },
+impl Default for Pricing {
+    fn default() -> Self {
+        Self {
+            subnet_size: SUBNET_SIZE,
+            ingress_message_received_cost: INGRESS_MESSAGE_RECEIVED_COST,
+            ingress_message_byte_received_cost: INGRESS_MESSAGE_BYTE_RECEIVED_COST,
+            http_outcall_request_base_cost: HTTP_OUTCALL_REQUEST_BASE_COST,
+            http_outcall_request_per_node_cost: HTTP_OUTCALL_REQUEST_PER_NODE_COST,
+            http_outcall_request_byte_cost: HTTP_OUTCALL_REQUEST_BYTE_COST,
+            http_outcall_response_byte_cost: HTTP_OUTCALL_RESPONSE_BYTE_COST,
+        }
+    }
 }
 
-#[macro_export]
-macro_rules! inc_metric {
-    ($metric:ident) => {{
-        METRICS.with(|m| m.borrow_mut().$metric += 1);
-    }};
+#[derive(Clone, Debug, Default, PartialEq, CandidType)]
+struct CostBreakdown {
+    ingress: u128,
+    outcall_base: u128,
+    outcall_bytes: u128,
+    provider_fee: u128,
+    total: u128,
 }
 
-#[macro_export]
-macro_rules! inc_metric_entry {
-    ($metric:ident, $entry:expr) => {{
-        METRICS.with(|m| {
-            m.borrow_mut()
-                .$metric
-                .entry($entry.clone())
-                .and_modify(|counter| *counter += 1)
-                .or_insert(1);
-        });
-    }};
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Deserialize)]
+enum BillingMode {
+    // Charge for max_response_bytes.
+    #[default]
+    MaxResponseBytes,
+    // Charge for max_response_bytes and refund the cost of the bytes not received.
+    ActualResponseBytes,
 }
 
-#[macro_export]
-macro_rules! add_metric {
-    ($metric:ident, $value:expr) => {{
-        METRICS.with(|m| m.borrow_mut().$metric += $value);
-    }};
+// Prepaid cycles of a caller.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct Account {
+    balance: u128,
+    // Index of the next entry in the usage history.
+    next_usage_index: u64,
+    // None is BillingMode::MaxResponseBytes.
+    billing_mode: Option<BillingMode>,
 }
 
-#[macro_export]
-macro_rules! get_metric {
-    ($metric:ident) => {{
-        METRICS.with(|m| m.borrow().$metric)
-    }};
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+struct UsageKey {
+    caller: Principal,
+    index: u64,
 }
 
-#[ic_cdk_macros::update]
-#[candid_method]
-async fn json_rpc_request(
-    json_rpc_payload: String,
-    service_url: String,
-    max_response_bytes: u64,
-) -> Result<Vec<u8>, EthRpcError> {
-    json_rpc_request_internal(json_rpc_payload, service_url, max_response_bytes, None).await
+// A request paid for from the prepaid balance.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Usage {
+    timestamp: u64,
+    host: String,
+    provider_id: Option<u64>,
+    cycles: u128,
 }
 
-#[ic_cdk_macros::update]
-#[candid_method]
-async fn json_rpc_provider_request(
-    json_rpc_payload: String,
-    provider_id: u64,
-    max_response_bytes: u64,
-) -> Result<Vec<u8>, EthRpcError> {
-    let provider = PROVIDERS.with(|p| {
-        p.borrow()
-            .get(&provider_id)
-            .ok_or(EthRpcError::ProviderNotFound)
-    });
-    let provider = provider?;
-    let service_url = provider.service_url.clone() + &provider.api_key;
-    json_rpc_request_internal(
-        json_rpc_payload,
-        service_url,
-        max_response_bytes,
-        Some(provider),
-    )
-    .await
+/// An allowlist entry for service URLs, e.g. "*.infura.io" or "rpc.ankr.com/eth_goerli".
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+struct ServiceHost {
+    scheme: String,
+    // A leading "*." matches any subdomain (but not the domain itself).
+    host: String,
+    // None matches only the default port of the scheme.
+    port: Option<u16>,
+    // Matches whole path segments, e.g. "/v3" matches "/v3" and "/v3/key" but not "/v3x".
+    path_prefix: Option<String>,
 }
 
-async fn json_rpc_request_internal(
-    json_rpc_payload: String,
-    service_url: String,
-    max_response_bytes: u64,
-    provider: Option<Provider>,
-) -> Result<Vec<u8>, EthRpcError> {
-    inc_metric!(json_rpc_requests);
-    if !authorized(Auth::Rpc) {
-        inc_metric!(json_rpc_request_err_no_permission);
-        return Err(EthRpcError::NoPermission);
-    }
-    let cycles_available = ic_cdk::api::call::msg_cycles_available128();
-    let parsed_url = url::Url::parse(&service_url).or(Err(EthRpcError::ServiceUrlParseError))?;
-    let host = parsed_url
-        .host_str()
-        .ok_or(EthRpcError::ServiceUrlHostMissing)?
-        .to_string();
-    if SERVICE_HOSTS_ALLOWLIST.with(|a| !a.borrow().contains(&host.as_str())) {
-        log!(INFO, "host not allowed {}", host);
-        inc_metric!(json_rpc_request_err_service_url_host_not_allowed);
-        return Err(EthRpcError::ServiceUrlHostNotAllowed);
-    }
-    if !authorized(Auth::FreeRpc) {
-        let provider_cost = match &provider {
-            None => 0,
-            Some(provider) => json_rpc_provider_cycles_cost(
-                &json_rpc_payload,
-                provider.cycles_per_call,
-                provider.cycles_per_message_byte,
+impl ServiceHost {
+    /// Parses the textual form "[scheme://]host[:port][/path/prefix]" (scheme defaults to https).
+    fn parse(text: &str) -> Result<Self, String> {
+        let (scheme, rest) = match text.trim().split_once("://") {
+            Some((scheme, rest)) => (scheme.to_lowercase(), rest),
+            None => ("https".to_string(), text.trim()),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, ""),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                Some(
+                    port.parse::<u16>()
+                        .map_err(|_| format!("invalid port in {}", text))?,
+                ),
             ),
+            None => (authority, None),
         };
-        let cost = json_rpc_cycles_cost(&json_rpc_payload, &service_url, max_response_bytes)
-            + provider_cost;
-        if cycles_available < cost {
-            return Err(EthRpcError::TooFewCycles(format!(
-                "requires {} cycles, got {} cycles",
-                cost, cycles_available
-            )));
-        }
-        ic_cdk::api::call::msg_cycles_accept128(cost);
-        if let Some(mut provider) = provider {
-            provider.cycles_owed += provider_cost;
-            PROVIDERS.with(|p| {
-                // Error should not happen here as it was checked before.
-                p.borrow_mut()
-                    .insert(provider.provider_id, provider)
-                    .expect("unable to update Provider");
-            });
+        let path = path.trim_end_matches('/');
+        let service_host = ServiceHost {
+            scheme,
+            host: host.to_lowercase(),
+            port,
+            path_prefix: if path.is_empty() {
+                None
+            } else {
+                Some(path.to_string())
+            },
+        };
+        service_host.validate()?;
+        Ok(service_host)
+    }
+
+    /// The validated key of the entry in SERVICE_HOSTS_ALLOWLIST.
+    fn key(&self) -> Result<String, String> {
+        self.validate()?;
+        let key = self.to_string();
+        if key.len() > STRING_STORABLE_MAX_SIZE as usize {
+            return Err("Service host too long".to_string());
         }
-        add_metric!(json_rpc_request_cycles_charged, cost);
-        add_metric!(json_rpc_request_cycles_refunded, cycles_available - cost);
+        Ok(key)
     }
-    inc_metric_entry!(json_rpc_host_requests, host);
-    let request_headers = vec![
-        HttpHeader {
-            name: "Content-Type".to_string(),
-            value: "application/json".to_string(),
-        },
-        HttpHeader {
-            name: "Host".to_string(),
-            value: host.to_string(),
-        },
-    ];
-    let request = CanisterHttpRequestArgument {
-        url: service_url,
-        max_response_bytes: Some(max_response_bytes),
-        method: HttpMethod::POST,
-        headers: request_headers,
-        body: Some(json_rpc_payload.as_bytes().to_vec()),
-        transform: Some(TransformContext::new(transform, vec![])),
-    };
-    match make_http_request(request).await {
-        Ok((result,)) => Ok(result.body),
-        Err((r, m)) => {
-            inc_metric!(json_rpc_request_err_http_request_error);
-            Err(EthRpcError::HttpRequestError {
-                code: r as u32,
-                message: m,
-            })
+
+    fn validate(&self) -> Result<(), String> {
+        if self.scheme != "https" && self.scheme != "http" {
+            return Err(format!("unsupported scheme {}", self.scheme));
         }
+        let domain = self.host.strip_prefix("*.").unwrap_or(&self.host);
+        if domain.is_empty()
+            || !domain
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        {
+            return Err(format!("invalid host {}", self.host));
+        }
+        if let Some(path_prefix) = &self.path_prefix {
+            if !path_prefix.starts_with('/') || path_prefix.ends_with('/') {
+                return Err(format!("invalid path prefix {}", path_prefix));
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, url: &url::Url) -> bool {
+        if url.scheme() != self.scheme {
+            return false;
+        }
+        let host = match url.host_str() {
+            Some(host) => host,
+            None => return false,
+        };
+        let host_matches = match self.host.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).map_or(false, |subdomain| {
+                subdomain.len() > 1 && subdomain.ends_with('.')
+            }),
+            None => host == self.host,
+        };
+        let port_matches = match self.port {
+            Some(port) => url.port_or_known_default() == Some(port),
+            None => url.port().is_none(),
+        };
+        let path_matches = match &self.path_prefix {
+            Some(path_prefix) => url
+                .path()
+                .strip_prefix(path_prefix.as_str())
+                .map_or(false, |rest| rest.is_empty() || rest.starts_with('/')),
+            None => true,
+        };
+        host_matches && port_matches && path_matches
     }
 }
 
-fn json_rpc_cycles_cost(
-    json_rpc_payload: &str,
-    service_url: &str,
-    max_response_bytes: u64,
-) -> u128 {
-    let ingress_bytes =
+impl std::fmt::Display for ServiceHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.scheme != "https" {
+            write!(f, "{}://", self.scheme)?;
+        }
+        write!(f, "{}", self.host)?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        if let Some(path_prefix) = &self.path_prefix {
+            write!(f, "{}", path_prefix)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum MultiProviders {
+    ProviderIds(Vec<u64>),
+    ChainId(u64),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum ConsensusStrategy {
+    // All providers must return the same result.
+    Equality,
+    // At least `min` of the first `total` providers must return the same result.
+    Threshold { min: u32, total: u32 },
+    // Any successful result is accepted.
+    Any,
+}
+
+impl ConsensusStrategy {
+    fn validate(&self) -> Result<(), EthRpcError> {
+        match self {
+            ConsensusStrategy::Threshold { min, total } if *min == 0 || min > total => {
+                Err(EthRpcError::InvalidConsensusStrategy(
+                    "Threshold requires 0 < min <= total".to_string(),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, CandidType)]
+struct ProviderResult {
+    provider_id: u64,
+    result: Result<Vec<u8>, EthRpcError>,
+}
+
+// Preferred order of the providers for a chain id.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct ProviderOrder(Vec<u64>);
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum BlockTag {
+    Latest,
+    Safe,
+    Finalized,
+    Earliest,
+    Pending,
+    Number(u64),
+}
+
+#[derive(Clone, Debug, CandidType)]
+struct Block {
+    number: u64,
+    hash: String,
+    parent_hash: String,
+    timestamp: u64,
+    miner: String,
+    state_root: String,
+    transactions_root: String,
+    receipts_root: String,
+    gas_limit: Nat,
+    gas_used: Nat,
+    base_fee_per_gas: Option<Nat>,
+    extra_data: String,
+    // Transaction hashes.
+    transactions: Vec<String>,
+}
+
+#[derive(Clone, Debug, CandidType)]
+struct LogEntry {
+    address: String,
+    topics: Vec<String>,
+    data: String,
+    // None for pending logs.
+    block_number: Option<u64>,
+    block_hash: Option<String>,
+    transaction_hash: Option<String>,
+    transaction_index: Option<u64>,
+    log_index: Option<u64>,
+    removed: bool,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct LogFilter {
+    // An empty list matches any address.
+    addresses: Vec<String>,
+    // Each position matches any of the given topics; an empty list matches any topic.
+    topics: Vec<Vec<String>>,
+    from_block: BlockTag,
+    to_block: BlockTag,
+}
+
+#[derive(Clone, Debug, CandidType)]
+struct TransactionReceipt {
+    transaction_hash: String,
+    transaction_index: u64,
+    block_hash: String,
+    block_number: u64,
+    from: String,
+    to: Option<String>,
+    contract_address: Option<String>,
+    cumulative_gas_used: Nat,
+    gas_used: Nat,
+    effective_gas_price: Option<Nat>,
+    // 1 for success and 0 for failure; None before the Byzantium fork.
+    status: Option<u64>,
+    logs: Vec<LogEntry>,
+}
+
+#[derive(Clone, Debug, CandidType)]
+struct FeeHistory {
+    oldest_block: u64,
+    base_fee_per_gas: Vec<Nat>,
+    gas_used_ratio: Vec<f64>,
+    reward: Vec<Vec<Nat>>,
+}
+
+// Passed to transform() as the TransformContext data.
+#[derive(Clone, Debug, Default, PartialEq, CandidType, Deserialize)]
+struct TransformOptions {
+    // Re-serialize JSON responses with sorted keys and no whitespace.
+    canonicalize: bool,
+    // Dot separated paths of fields to remove from JSON responses.
+    drop_fields: Vec<String>,
+    // Fields of JSON responses which are set to a fixed value if present.
+    set_fields: Vec<SetField>,
+}
+
+#[derive(Clone, Debug, PartialEq, CandidType, Deserialize)]
+struct SetField {
+    // Dot separated path of the field, e.g. "id".
+    path: String,
+    // JSON encoded.
+    value: String,
+}
+
+// The transform of the responses of a request. The default is the preset for the method of the
+// request, see TRANSFORM_PRESETS.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+enum Transform {
+    // The preset for a JSON RPC method, e.g. "eth_getBlockByNumber".
+    Preset(String),
+    Options(TransformOptions),
+}
+
+impl Storable for Metadata {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+impl Storable for Provider {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let mut bytes = vec![PROVIDER_STORAGE_VERSION];
+        bytes.extend(Encode!(self).unwrap());
+        Cow::Owned(bytes)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        match bytes[0] {
+            PROVIDER_STORAGE_VERSION => Decode!(&bytes[1..], Self).unwrap(),
+            version => panic!("unsupported provider storage version {}", version),
+        }
+    }
+}
+
+impl BoundedStorable for Provider {
+    const MAX_SIZE: u32 = PROVIDER_MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Unversioned providers stored before the versioned encoding, see migrate_providers.
+struct LegacyProvider(Provider);
+
+impl Storable for LegacyProvider {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(Decode!(&bytes, Provider).unwrap())
+    }
+}
+
+impl BoundedStorable for LegacyProvider {
+    const MAX_SIZE: u32 = LEGACY_PROVIDER_MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+impl Storable for Pricing {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+impl Storable for Account {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Account {
+    const MAX_SIZE: u32 = 256; // A reasonable limit.
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for UsageKey {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        let principal = self.caller.as_slice();
+        let mut bytes = vec![0; 38];
+        bytes[0] = principal.len() as u8;
+        bytes[1..1 + principal.len()].copy_from_slice(principal);
+        bytes[30..].copy_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let len = bytes[0] as usize;
+        Self {
+            caller: Principal::from_slice(&bytes[1..1 + len]),
+            index: u64::from_be_bytes(bytes[30..38].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for UsageKey {
+    const MAX_SIZE: u32 = 38;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+impl Storable for Usage {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Usage {
+    const MAX_SIZE: u32 = 512; // Enough for a maximum length host.
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ProviderOrder {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ProviderOrder {
+    const MAX_SIZE: u32 = 512; // Enough for PROVIDER_ORDER_MAX_LEN ids.
+    const IS_FIXED_SIZE: bool = false;
+}
+
+thread_local! {
+    // Transient static data: this is reset when the canister is upgraded.
+    static METRICS: RefCell<Metrics> = RefCell::new(Metrics::default());
+    static RESPONSE_SIZES: RefCell<HashMap<ResponseSizeKey, VecDeque<u64>>> = RefCell::new(HashMap::new());
+    static AUTH_STABLE: RefCell<HashSet<Principal>> = RefCell::new(HashSet::<Principal>::new());
+
+    // Stable static data: this is preserved when the canister is upgraded.
+    #[cfg(not(any(target_arch = "wasm32", test)))]
+    static MEMORY_MANAGER: RefCell<MemoryManager<FileMemory>> =
+        RefCell::new(MemoryManager::init(FileMemory::new(File::open("stable_memory.bin").unwrap())));
+    #[cfg(any(target_arch = "wasm32", test))]
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    static METADATA: RefCell<Cell<Metadata, Memory>> = RefCell::new(Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+            <Metadata>::default()).unwrap());
+    static LEGACY_AUTH: RefCell<StableBTreeMap<PrincipalStorable, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))));
+    static LEGACY_PROVIDERS: RefCell<StableBTreeMap<u64, LegacyProvider, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))));
+    static SERVICE_HOSTS_ALLOWLIST: RefCell<StableBTreeMap<StringStorable, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))));
+    static PROVIDER_ORDER: RefCell<StableBTreeMap<u64, ProviderOrder, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))));
+    static ACCOUNTS: RefCell<StableBTreeMap<PrincipalStorable, Account, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))));
+    static ACCOUNT_USAGE: RefCell<StableBTreeMap<UsageKey, Usage, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))));
+    static PRICING: RefCell<Cell<Pricing, Memory>> = RefCell::new(Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+            <Pricing>::default()).unwrap());
+    static PROVIDERS: RefCell<StableBTreeMap<u64, Provider, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))));
+    static AUTH: RefCell<StableBTreeMap<PrincipalStorable, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))));
+    static CONFIG: RefCell<Cell<Config, Memory>> = RefCell::new(Cell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+            <Config>::default()).unwrap());
+    static RPC_DENYLIST: RefCell<StableBTreeMap<PrincipalStorable, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))));
+}
+
+#[derive(CandidType, Debug)]
+enum EthRpcError {
+    NoPermission,
+    TooFewCycles(String),
+    ServiceUrlParseError,
+    ServiceUrlHostMissing,
+    ServiceUrlHostNotAllowed,
+    ProviderNotFound,
+    ProviderDisabled,
+    InvalidProvider(String),
+    InvalidBlockRange(String),
+    InvalidTransform(String),
+    InvalidConsensusStrategy(String),
+    HttpRequestError {
+        code: u32,
+        message: String,
+    },
+    Inconsistent(Vec<ProviderResult>),
+    ResponseParseError(String),
+    HttpStatus {
+        code: u16,
+        body: String,
+    },
+    JsonRpcError {
+        code: i64,
+        message: String,
+        // JSON encoded.
+        data: Option<String>,
+    },
+}
+
+#[macro_export]
+macro_rules! inc_metric {
+    ($metric:ident) => {{
+        METRICS.with(|m| m.borrow_mut().$metric += 1);
+    }};
+}
+
+#[macro_export]
+macro_rules! inc_metric_entry {
+    ($metric:ident, $entry:expr) => {{
+        METRICS.with(|m| {
+            m.borrow_mut()
+                .$metric
+                .entry($entry.clone())
+                .and_modify(|counter| *counter += 1)
+                .or_insert(1);
+        });
+    }};
+}
+
+#[macro_export]
+macro_rules! add_metric {
+    ($metric:ident, $value:expr) => {{
+        METRICS.with(|m| m.borrow_mut().$metric += $value);
+    }};
+}
+
+#[macro_export]
+macro_rules! get_metric {
+    ($metric:ident) => {{
+        METRICS.with(|m| m.borrow().$metric)
+    }};
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn json_rpc_request(
+    json_rpc_payload: String,
+    service_url: String,
+    max_response_bytes: u64,
+    transform: Option<Transform>,
+) -> Result<Vec<u8>, EthRpcError> {
+    json_rpc_request_internal(
+        json_rpc_payload,
+        service_url,
+        max_response_bytes,
+        None,
+        transform,
+        &HeldCycles::default(),
+    )
+    .await
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn json_rpc_provider_request(
+    json_rpc_payload: String,
+    provider_id: u64,
+    max_response_bytes: u64,
+    transform: Option<Transform>,
+) -> Result<Vec<u8>, EthRpcError> {
+    let provider = PROVIDERS.with(|p| {
+        p.borrow()
+            .get(&provider_id)
+            .ok_or(EthRpcError::ProviderNotFound)
+    });
+    json_rpc_provider_request_internal(
+        json_rpc_payload,
+        provider?,
+        max_response_bytes,
+        transform,
+        &HeldCycles::default(),
+    )
+    .await
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn json_rpc_multi_request(
+    json_rpc_payload: String,
+    providers: MultiProviders,
+    strategy: ConsensusStrategy,
+    max_response_bytes: u64,
+    transform: Option<Transform>,
+) -> Result<Vec<u8>, EthRpcError> {
+    inc_metric!(json_rpc_multi_requests);
+    let mut providers = match providers {
+        MultiProviders::ProviderIds(provider_ids) => {
+            let mut providers = Vec::<Provider>::new();
+            for provider_id in provider_ids {
+                if providers.iter().any(|p| p.provider_id == provider_id) {
+                    continue;
+                }
+                let provider = PROVIDERS.with(|p| p.borrow().get(&provider_id));
+                providers.push(provider.ok_or(EthRpcError::ProviderNotFound)?);
+            }
+            providers
+        }
+        MultiProviders::ChainId(chain_id) => chain_providers(chain_id),
+    };
+    strategy.validate()?;
+    if let ConsensusStrategy::Threshold { total, .. } = strategy {
+        providers.truncate(total as usize);
+    }
+    if providers.is_empty() {
+        return Err(EthRpcError::ProviderNotFound);
+    }
+    // The concurrent requests share the cycles attached to the call.
+    let held = &HeldCycles::default();
+    let results = futures::future::join_all(providers.into_iter().map(|provider| {
+        let json_rpc_payload = json_rpc_payload.clone();
+        let transform = transform.clone();
+        async move {
+            ProviderResult {
+                provider_id: provider.provider_id,
+                result: json_rpc_provider_request_internal(
+                    json_rpc_payload,
+                    provider,
+                    max_response_bytes,
+                    transform,
+                    held,
+                )
+                .await,
+            }
+        }
+    }))
+    .await;
+    let result = consensus(&strategy, results);
+    if let Err(EthRpcError::Inconsistent(_)) = result {
+        inc_metric!(json_rpc_multi_request_err_inconsistent);
+    }
+    result
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn json_rpc_chain_request(
+    json_rpc_payload: String,
+    chain_id: u64,
+    max_response_bytes: u64,
+    transform: Option<Transform>,
+) -> Result<Vec<u8>, EthRpcError> {
+    inc_metric!(json_rpc_chain_requests);
+    let mut result = Err(EthRpcError::ProviderNotFound);
+    let held = HeldCycles::default();
+    for provider in chain_providers(chain_id) {
+        let provider_id = provider.provider_id;
+        result = json_rpc_provider_request_internal(
+            json_rpc_payload.clone(),
+            provider,
+            max_response_bytes,
+            transform.clone(),
+            &held,
+        )
+        .await;
+        if !should_failover(&result) {
+            break;
+        }
+        log!(INFO, "provider {} failed, failing over", provider_id);
+        inc_metric!(json_rpc_chain_request_failovers);
+    }
+    result
+}
+
+async fn json_rpc_provider_request_internal(
+    json_rpc_payload: String,
+    provider: Provider,
+    max_response_bytes: u64,
+    transform: Option<Transform>,
+    held: &HeldCycles,
+) -> Result<Vec<u8>, EthRpcError> {
+    if provider.status(ic_cdk::api::time()) != ProviderStatus::Active {
+        return Err(EthRpcError::ProviderDisabled);
+    }
+    let service_url = provider.url();
+    json_rpc_request_internal(
+        json_rpc_payload,
+        service_url,
+        max_response_bytes,
+        Some(provider),
+        transform,
+        held,
+    )
+    .await
+}
+
+/// Active providers for the chain id, in the configured order followed by the rest by
+/// provider id.
+fn chain_providers(chain_id: u64) -> Vec<Provider> {
+    let order = PROVIDER_ORDER.with(|o| o.borrow().get(&chain_id).unwrap_or_default());
+    let time = ic_cdk::api::time();
+    let mut providers: Vec<Provider> = PROVIDERS.with(|p| {
+        p.borrow()
+            .iter()
+            .map(|(_, provider)| provider)
+            .filter(|provider| {
+                provider.chain_id == chain_id && provider.status(time) == ProviderStatus::Active
+            })
+            .collect()
+    });
+    providers.sort_by_key(|provider| {
+        order
+            .0
+            .iter()
+            .position(|id| *id == provider.provider_id)
+            .unwrap_or(order.0.len())
+    });
+    providers
+}
+
+/// Whether to retry a request with the next provider.
+fn should_failover(result: &Result<Vec<u8>, EthRpcError>) -> bool {
+    match result {
+        Ok(_) => false,
+        Err(EthRpcError::HttpRequestError { .. }) => true,
+        // Rate limited or server errors.
+        Err(EthRpcError::HttpStatus { code, .. }) => *code == 429 || *code >= 500,
+        Err(EthRpcError::JsonRpcError { code, .. }) => is_json_rpc_server_error(*code),
+        Err(_) => false,
+    }
+}
+
+/// Whether the code is a JSON RPC internal (-32603) or server (-32000 to -32099) error.
+fn is_json_rpc_server_error(code: i64) -> bool {
+    code == -32603 || (-32099..=-32000).contains(&code)
+}
+
+/// Returns the canonical JSON of the result agreed on according to the strategy.
+fn consensus(
+    strategy: &ConsensusStrategy,
+    results: Vec<ProviderResult>,
+) -> Result<Vec<u8>, EthRpcError> {
+    let canonical: Vec<Option<Vec<u8>>> = results
+        .iter()
+        .map(|r| r.result.as_ref().ok().map(|body| canonical_json(body)))
+        .collect();
+    let agreed = match strategy {
+        ConsensusStrategy::Any => canonical.iter().flatten().next().cloned(),
+        ConsensusStrategy::Equality => match canonical.first() {
+            Some(Some(first)) if canonical.iter().all(|c| c.as_ref() == Some(first)) => {
+                Some(first.clone())
+            }
+            _ => None,
+        },
+        ConsensusStrategy::Threshold { min, .. } => {
+            let mut counts = Vec::<(&Vec<u8>, u32)>::new();
+            for c in canonical.iter().flatten() {
+                match counts.iter_mut().find(|(v, _)| *v == c) {
+                    Some((_, count)) => *count += 1,
+                    None => counts.push((c, 1)),
+                }
+            }
+            counts
+                .into_iter()
+                .find(|(_, count)| count >= min)
+                .map(|(v, _)| v.clone())
+        }
+    };
+    agreed.ok_or(EthRpcError::Inconsistent(results))
+}
+
+/// Re-serializes JSON with sorted keys and no whitespace. Non-JSON is returned as is.
+/// The keys are sorted as serde_json::Map is a BTreeMap without the preserve_order feature,
+/// and numbers are kept as they are with the arbitrary_precision feature.
+fn canonical_json(body: &[u8]) -> Vec<u8> {
+    match serde_json::from_slice::<serde_json::Value>(body) {
+        Ok(value) => serde_json::to_vec(&value).unwrap_or_else(|_| body.to_vec()),
+        Err(_) => body.to_vec(),
+    }
+}
+
+async fn json_rpc_request_internal(
+    json_rpc_payload: String,
+    service_url: String,
+    max_response_bytes: u64,
+    provider: Option<Provider>,
+    transform: Option<Transform>,
+    held: &HeldCycles,
+) -> Result<Vec<u8>, EthRpcError> {
+    if !rpc_access_allowed(ic_cdk::caller()) {
+        inc_metric!(json_rpc_request_err_no_permission);
+        return Err(EthRpcError::NoPermission);
+    }
+    let options = transform_options(&json_rpc_payload, transform)?;
+    if max_response_bytes != 0 {
+        return json_rpc_outcall(
+            json_rpc_payload,
+            service_url,
+            max_response_bytes,
+            provider,
+            &options,
+            held,
+        )
+        .await;
+    }
+    let provider_id = provider.as_ref().map(|p| p.provider_id);
+    let max_response_bytes = auto_max_response_bytes(&json_rpc_payload, provider_id, &service_url);
+    let result = json_rpc_outcall(
+        json_rpc_payload.clone(),
+        service_url.clone(),
+        max_response_bytes,
+        provider.clone(),
+        &options,
+        held,
+    )
+    .await;
+    match result {
+        Err(EthRpcError::HttpRequestError { message, .. })
+            if is_response_too_large(&message) && max_response_bytes < MAX_RESPONSE_BYTES =>
+        {
+            inc_metric!(json_rpc_request_auto_max_response_bytes_retries);
+            let max_response_bytes = (max_response_bytes * 2).min(MAX_RESPONSE_BYTES);
+            json_rpc_outcall(
+                json_rpc_payload,
+                service_url,
+                max_response_bytes,
+                provider,
+                &options,
+                held,
+            )
+            .await
+        }
+        result => result,
+    }
+}
+
+async fn json_rpc_outcall(
+    json_rpc_payload: String,
+    service_url: String,
+    max_response_bytes: u64,
+    provider: Option<Provider>,
+    options: &TransformOptions,
+    held: &HeldCycles,
+) -> Result<Vec<u8>, EthRpcError> {
+    inc_metric!(json_rpc_requests);
+    let cycles_available = ic_cdk::api::call::msg_cycles_available128();
+    let parsed_url = url::Url::parse(&service_url).or(Err(EthRpcError::ServiceUrlParseError))?;
+    let host = parsed_url
+        .host_str()
+        .ok_or(EthRpcError::ServiceUrlHostMissing)?
+        .to_string();
+    if !service_url_allowed(&parsed_url) {
+        log!(INFO, "host not allowed {}", host);
+        inc_metric!(json_rpc_request_err_service_url_host_not_allowed);
+        return Err(EthRpcError::ServiceUrlHostNotAllowed);
+    }
+    let charged = !authorized(Auth::FreeRpc);
+    let mut payment = Payment::Free;
+    // The provider fee for the maximum response size is charged and the fee for the
+    // actual response size is credited to the provider after the outcall.
+    let mut provider_cost = 0;
+    let mut outcall_cost = 0;
+    let pricing = get_pricing();
+    let request_header_bytes = provider.as_ref().map_or(0, |p| p.request_header_bytes());
+    let request_bytes = json_rpc_payload.len() as u64 + request_header_bytes;
+    if charged {
+        if let Some(provider) = &provider {
+            provider_cost = json_rpc_provider_cycles_cost(
+                request_bytes,
+                max_response_bytes,
+                provider.cycles_per_call,
+                provider.cycles_per_message_byte,
+            );
+        }
+        let breakdown = json_rpc_cost_breakdown(
+            &pricing,
+            &json_rpc_payload,
+            &service_url,
+            request_header_bytes,
+            max_response_bytes,
+            provider_cost,
+        );
+        outcall_cost = breakdown.outcall_base + breakdown.outcall_bytes;
+        let cost = breakdown.total;
+        // Callers which attach no cycles pay from their prepaid balance.
+        if cycles_available == 0 {
+            let usage_index = debit_balance(
+                ic_cdk::caller(),
+                Usage {
+                    timestamp: ic_cdk::api::time(),
+                    host: host.clone(),
+                    provider_id: provider.as_ref().map(|p| p.provider_id),
+                    cycles: cost,
+                },
+            )?;
+            payment = Payment::Balance { usage_index };
+        } else {
+            // Cycles held by other requests of the call, e.g. of json_rpc_multi_request, are
+            // not available.
+            let cycles_available = cycles_available.saturating_sub(held.get());
+            if cycles_available < cost {
+                return Err(EthRpcError::TooFewCycles(format!(
+                    "requires {} cycles, got {} cycles",
+                    cost, cycles_available
+                )));
+            }
+            held.hold(cost);
+            payment = Payment::Attached { held: cost };
+        }
+        add_metric!(json_rpc_request_cycles_charged, cost);
+    }
+    inc_metric_entry!(json_rpc_host_requests, host);
+    let mut request_headers = vec![
+        HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
+        HttpHeader {
+            name: "Host".to_string(),
+            value: host.to_string(),
+        },
+    ];
+    if let Some(provider) = &provider {
+        request_headers.extend(provider.request_headers());
+    }
+    let request = CanisterHttpRequestArgument {
+        url: service_url,
+        max_response_bytes: Some(max_response_bytes),
+        method: HttpMethod::POST,
+        headers: request_headers,
+        body: Some(json_rpc_payload.as_bytes().to_vec()),
+        transform: Some(TransformContext::new(transform, Encode!(options).unwrap())),
+    };
+    let response = make_http_request(request).await;
+    if let Ok((result,)) = &response {
+        record_response_size(
+            &json_rpc_payload,
+            provider.as_ref().map(|p| p.provider_id),
+            &host,
+            raw_response_bytes_bound(result.body.len() as u64),
+        );
+    }
+    if charged {
+        // The cycles charged but not used, refunded the way the request was paid.
+        let mut refund = 0;
+        if let Some(provider) = &provider {
+            let response_bytes = match &response {
+                Ok((result,)) => Some(result.body.len() as u64),
+                Err(_) => None,
+            };
+            refund += settle_provider_fee(provider, request_bytes, response_bytes, provider_cost);
+        }
+        if let Ok((result,)) = &response {
+            let response_bytes = (result.body.len() as u64).min(max_response_bytes);
+            add_metric!(json_rpc_request_response_bytes_reserved, max_response_bytes);
+            add_metric!(json_rpc_request_response_bytes_received, response_bytes);
+            let caller = ic_cdk::caller();
+            if get_account(caller).billing_mode == Some(BillingMode::ActualResponseBytes) {
+                let bytes_refund = unused_response_bytes_cost(
+                    &pricing,
+                    max_response_bytes,
+                    raw_response_bytes_bound(response_bytes),
+                );
+                refund += bytes_refund;
+                add_metric!(
+                    json_rpc_request_response_bytes_cycles_refunded,
+                    bytes_refund
+                );
+            }
+        }
+        if response.is_err() {
+            // Only the cycles consumed by the failed outcall are charged.
+            let outcall_refund = ic_cdk::api::call::msg_cycles_refunded128().min(outcall_cost);
+            refund += outcall_refund;
+            add_metric!(
+                json_rpc_request_err_http_request_error_cycles_refunded,
+                outcall_refund
+            );
+        }
+        settle_payment(payment, refund, held);
+    }
+    match response {
+        Ok((result,)) => {
+            let result = http_response_result(&result.status, result.body);
+            match result {
+                Err(EthRpcError::HttpStatus { .. }) => {
+                    inc_metric!(json_rpc_request_err_http_status)
+                }
+                Err(EthRpcError::JsonRpcError { .. }) => {
+                    inc_metric!(json_rpc_request_err_json_rpc_error)
+                }
+                _ => (),
+            }
+            result
+        }
+        Err((r, m)) => {
+            inc_metric!(json_rpc_request_err_http_request_error);
+            Err(EthRpcError::HttpRequestError {
+                code: r as u32,
+                message: m,
+            })
+        }
+    }
+}
+
+/// Whether the outcall failed because the response was larger than max_response_bytes.
+fn is_response_too_large(message: &str) -> bool {
+    message.to_lowercase().contains("size limit")
+}
+
+fn response_size_key(
+    json_rpc_payload: &str,
+    provider_id: Option<u64>,
+    host: &str,
+) -> ResponseSizeKey {
+    ResponseSizeKey {
+        method: json_rpc_method(json_rpc_payload).unwrap_or_default(),
+        provider_id,
+        host: host.to_string(),
+    }
+}
+
+fn record_response_size(
+    json_rpc_payload: &str,
+    provider_id: Option<u64>,
+    host: &str,
+    response_bytes: u64,
+) {
+    let key = response_size_key(json_rpc_payload, provider_id, host);
+    if key.method.len() > AUTO_RESPONSE_SIZE_MAX_METHOD_LEN {
+        return;
+    }
+    RESPONSE_SIZES.with(|r| {
+        let mut r = r.borrow_mut();
+        if !r.contains_key(&key) && r.len() >= AUTO_RESPONSE_SIZE_MAX_KEYS {
+            return;
+        }
+        let sizes = r.entry(key).or_default();
+        if sizes.len() == AUTO_RESPONSE_SIZE_SAMPLES {
+            sizes.pop_front();
+        }
+        sizes.push_back(response_bytes);
+    });
+}
+
+/// The max_response_bytes used when it is 0 (auto).
+fn auto_max_response_bytes(
+    json_rpc_payload: &str,
+    provider_id: Option<u64>,
+    service_url: &str,
+) -> u64 {
+    let host = url::Url::parse(service_url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_string()))
+        .unwrap_or_default();
+    let key = response_size_key(json_rpc_payload, provider_id, &host);
+    RESPONSE_SIZES.with(|r| match r.borrow().get(&key) {
+        Some(sizes) => response_size_limit(sizes),
+        None => AUTO_MAX_RESPONSE_BYTES_DEFAULT,
+    })
+}
+
+fn response_size_limit(sizes: &VecDeque<u64>) -> u64 {
+    if sizes.is_empty() {
+        return AUTO_MAX_RESPONSE_BYTES_DEFAULT;
+    }
+    let mut sorted: Vec<u64> = sizes.iter().cloned().collect();
+    sorted.sort_unstable();
+    // The nearest-rank percentile.
+    let rank = (sorted.len() * AUTO_RESPONSE_SIZE_PERCENTILE + 99) / 100;
+    let percentile = sorted[rank.max(1) - 1];
+    (percentile + percentile / 4).min(MAX_RESPONSE_BYTES)
+}
+
+/// Returns an error for non-2xx HTTP statuses and JSON RPC error responses.
+fn http_response_result(status: &Nat, body: Vec<u8>) -> Result<Vec<u8>, EthRpcError> {
+    let code = status.0.to_u16().unwrap_or(u16::MAX);
+    if !(200..300).contains(&code) {
+        return Err(EthRpcError::HttpStatus {
+            code,
+            body: String::from_utf8_lossy(&body).to_string(),
+        });
+    }
+    // Bodies which are not JSON RPC responses are returned as is.
+    match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(response) => match json_rpc_error(&response) {
+            Some(error) => Err(error),
+            None => Ok(body),
+        },
+        Err(_) => Ok(body),
+    }
+}
+
+/// Returns the error of a JSON RPC response, if any. Some providers send `"error": null` with
+/// a result.
+fn json_rpc_error(response: &serde_json::Value) -> Option<EthRpcError> {
+    let error = response.get("error").filter(|error| !error.is_null())?;
+    Some(EthRpcError::JsonRpcError {
+        code: error
+            .get("code")
+            .and_then(serde_json::Value::as_i64)
+            .unwrap_or(0),
+        message: error
+            .get("message")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default()
+            .to_string(),
+        data: error.get("data").map(|data| data.to_string()),
+    })
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn eth_block_number(provider_id: u64, max_response_bytes: u64) -> Result<u64, EthRpcError> {
+    let result = eth_rpc_call(
+        "eth_blockNumber",
+        serde_json::json!([]),
+        provider_id,
+        max_response_bytes,
+    )
+    .await?;
+    decode_u64(&result)
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn eth_get_balance(
+    address: String,
+    block: BlockTag,
+    provider_id: u64,
+    max_response_bytes: u64,
+) -> Result<Nat, EthRpcError> {
+    let result = eth_rpc_call(
+        "eth_getBalance",
+        serde_json::json!([address, block.to_json()]),
+        provider_id,
+        max_response_bytes,
+    )
+    .await?;
+    decode_nat(&result)
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn eth_get_block_by_number(
+    block: BlockTag,
+    provider_id: u64,
+    max_response_bytes: u64,
+) -> Result<Option<Block>, EthRpcError> {
+    let result = eth_rpc_call(
+        "eth_getBlockByNumber",
+        serde_json::json!([block.to_json(), false]),
+        provider_id,
+        max_response_bytes,
+    )
+    .await?;
+    if result.is_null() {
+        return Ok(None);
+    }
+    decode_block(&result).map(Some)
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn eth_get_transaction_receipt(
+    transaction_hash: String,
+    provider_id: u64,
+    max_response_bytes: u64,
+) -> Result<Option<TransactionReceipt>, EthRpcError> {
+    let result = eth_rpc_call(
+        "eth_getTransactionReceipt",
+        serde_json::json!([transaction_hash]),
+        provider_id,
+        max_response_bytes,
+    )
+    .await?;
+    if result.is_null() {
+        return Ok(None);
+    }
+    decode_transaction_receipt(&result).map(Some)
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn eth_fee_history(
+    block_count: u64,
+    newest_block: BlockTag,
+    reward_percentiles: Vec<f64>,
+    provider_id: u64,
+    max_response_bytes: u64,
+) -> Result<FeeHistory, EthRpcError> {
+    let result = eth_rpc_call(
+        "eth_feeHistory",
+        serde_json::json!([
+            format!("{:#x}", block_count),
+            newest_block.to_json(),
+            reward_percentiles
+        ]),
+        provider_id,
+        max_response_bytes,
+    )
+    .await?;
+    decode_fee_history(&result)
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn eth_send_raw_transaction(
+    raw_signed_transaction: String,
+    provider_id: u64,
+    max_response_bytes: u64,
+) -> Result<String, EthRpcError> {
+    let result = eth_rpc_call(
+        "eth_sendRawTransaction",
+        serde_json::json!([raw_signed_transaction]),
+        provider_id,
+        max_response_bytes,
+    )
+    .await?;
+    decode_string(&result)
+}
+
+#[ic_cdk_macros::update]
+#[candid_method]
+async fn eth_get_logs(
+    filter: LogFilter,
+    provider_id: u64,
+    max_response_bytes: u64,
+) -> Result<Vec<LogEntry>, EthRpcError> {
+    inc_metric!(eth_get_logs_requests);
+    let from_block =
+        resolve_block_number(&filter.from_block, provider_id, max_response_bytes).await?;
+    let to_block = resolve_block_number(&filter.to_block, provider_id, max_response_bytes).await?;
+    let mut ranges = log_block_ranges(
+        from_block,
+        to_block,
+        ETH_GET_LOGS_CHUNK_BLOCKS,
+        ETH_GET_LOGS_MAX_CHUNKS,
+    )?;
+    // Splits are additional requests and count against the same limit.
+    let mut splits_left = ETH_GET_LOGS_MAX_CHUNKS - ranges.len() as u64;
+    // Ranges are processed from the end of the list so that logs are returned in block order.
+    ranges.reverse();
+    let mut logs = vec![];
+    while let Some((from, to)) = ranges.pop() {
+        let result = eth_rpc_call(
+            "eth_getLogs",
+            log_filter_params(&filter, from, to),
+            provider_id,
+            max_response_bytes,
+        )
+        .await;
+        match result {
+            Ok(result) => logs.extend(decode_vec(&result, decode_log_entry)?),
+            Err(e) if from < to && splits_left > 0 && should_split_log_range(&e) => {
+                inc_metric!(eth_get_logs_range_splits);
+                splits_left -= 1;
+                let mid = from + (to - from) / 2;
+                ranges.push((mid + 1, to));
+                ranges.push((from, mid));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(logs)
+}
+
+async fn resolve_block_number(
+    block: &BlockTag,
+    provider_id: u64,
+    max_response_bytes: u64,
+) -> Result<u64, EthRpcError> {
+    match block {
+        BlockTag::Number(number) => Ok(*number),
+        BlockTag::Earliest => Ok(0),
+        BlockTag::Latest | BlockTag::Pending => {
+            eth_block_number(provider_id, max_response_bytes).await
+        }
+        BlockTag::Safe | BlockTag::Finalized => {
+            let result = eth_rpc_call(
+                "eth_getBlockByNumber",
+                serde_json::json!([block.to_json(), false]),
+                provider_id,
+                max_response_bytes,
+            )
+            .await?;
+            decode_u64(field(&result, "number")?)
+        }
+    }
+}
+
+/// Splits the inclusive range [from_block, to_block] into at most `max_chunks` ranges of at most
+/// `chunk` blocks.
+fn log_block_ranges(
+    from_block: u64,
+    to_block: u64,
+    chunk: u64,
+    max_chunks: u64,
+) -> Result<Vec<(u64, u64)>, EthRpcError> {
+    if to_block < from_block {
+        return Err(EthRpcError::InvalidBlockRange(format!(
+            "to_block {} is before from_block {}",
+            to_block, from_block
+        )));
+    }
+    let chunks = ((to_block - from_block) / chunk).saturating_add(1);
+    if chunks > max_chunks {
+        return Err(EthRpcError::InvalidBlockRange(format!(
+            "{} blocks exceed the limit of {} blocks",
+            (to_block - from_block).saturating_add(1),
+            chunk * max_chunks
+        )));
+    }
+    let mut ranges = vec![];
+    let mut from = from_block;
+    while from <= to_block {
+        let to = to_block.min(from.saturating_add(chunk - 1));
+        ranges.push((from, to));
+        if to == u64::MAX {
+            break;
+        }
+        from = to + 1;
+    }
+    Ok(ranges)
+}
+
+fn log_filter_params(filter: &LogFilter, from_block: u64, to_block: u64) -> serde_json::Value {
+    let mut params = serde_json::json!({
+        "fromBlock": BlockTag::Number(from_block).to_json(),
+        "toBlock": BlockTag::Number(to_block).to_json(),
+    });
+    if !filter.addresses.is_empty() {
+        params["address"] = serde_json::json!(filter.addresses);
+    }
+    if !filter.topics.is_empty() {
+        params["topics"] = filter
+            .topics
+            .iter()
+            .map(|topics| match topics.len() {
+                0 => serde_json::Value::Null,
+                1 => serde_json::json!(topics[0]),
+                _ => serde_json::json!(topics),
+            })
+            .collect();
+    }
+    serde_json::json!([params])
+}
+
+/// Returns true if the error indicates that the block range returned too many logs.
+fn should_split_log_range(error: &EthRpcError) -> bool {
+    let message = match error {
+        EthRpcError::HttpRequestError { message, .. } => message,
+        // Limit exceeded.
+        EthRpcError::JsonRpcError { code: -32005, .. } => return true,
+        EthRpcError::JsonRpcError { message, .. } => message,
+        _ => return false,
+    };
+    let message = message.to_lowercase();
+    [
+        "size limit",
+        "too many",
+        "more than",
+        "response size",
+        "block range",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+/// Makes a JSON RPC call via json_rpc_provider_request() and returns the `result`.
+async fn eth_rpc_call(
+    method: &str,
+    params: serde_json::Value,
+    provider_id: u64,
+    max_response_bytes: u64,
+) -> Result<serde_json::Value, EthRpcError> {
+    let json_rpc_payload = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+        "id": 1,
+    })
+    .to_string();
+    let body =
+        json_rpc_provider_request(json_rpc_payload, provider_id, max_response_bytes, None).await?;
+    json_rpc_result(&body)
+}
+
+fn json_rpc_result(body: &[u8]) -> Result<serde_json::Value, EthRpcError> {
+    let mut response: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| EthRpcError::ResponseParseError(e.to_string()))?;
+    if let Some(error) = json_rpc_error(&response) {
+        return Err(error);
+    }
+    response
+        .get_mut("result")
+        .map(serde_json::Value::take)
+        .ok_or_else(|| EthRpcError::ResponseParseError("missing result".to_string()))
+}
+
+impl BlockTag {
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            BlockTag::Latest => "latest".into(),
+            BlockTag::Safe => "safe".into(),
+            BlockTag::Finalized => "finalized".into(),
+            BlockTag::Earliest => "earliest".into(),
+            BlockTag::Pending => "pending".into(),
+            BlockTag::Number(number) => format!("{:#x}", number).into(),
+        }
+    }
+}
+
+fn decode_block(value: &serde_json::Value) -> Result<Block, EthRpcError> {
+    Ok(Block {
+        number: decode_u64(field(value, "number")?)?,
+        hash: decode_string(field(value, "hash")?)?,
+        parent_hash: decode_string(field(value, "parentHash")?)?,
+        timestamp: decode_u64(field(value, "timestamp")?)?,
+        miner: decode_string(field(value, "miner")?)?,
+        state_root: decode_string(field(value, "stateRoot")?)?,
+        transactions_root: decode_string(field(value, "transactionsRoot")?)?,
+        receipts_root: decode_string(field(value, "receiptsRoot")?)?,
+        gas_limit: decode_nat(field(value, "gasLimit")?)?,
+        gas_used: decode_nat(field(value, "gasUsed")?)?,
+        base_fee_per_gas: decode_opt(value, "baseFeePerGas", decode_nat)?,
+        extra_data: decode_string(field(value, "extraData")?)?,
+        transactions: decode_vec(field(value, "transactions")?, decode_string)?,
+    })
+}
+
+fn decode_log_entry(value: &serde_json::Value) -> Result<LogEntry, EthRpcError> {
+    Ok(LogEntry {
+        address: decode_string(field(value, "address")?)?,
+        topics: decode_vec(field(value, "topics")?, decode_string)?,
+        data: decode_string(field(value, "data")?)?,
+        block_number: decode_opt(value, "blockNumber", decode_u64)?,
+        block_hash: decode_opt(value, "blockHash", decode_string)?,
+        transaction_hash: decode_opt(value, "transactionHash", decode_string)?,
+        transaction_index: decode_opt(value, "transactionIndex", decode_u64)?,
+        log_index: decode_opt(value, "logIndex", decode_u64)?,
+        removed: value
+            .get("removed")
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false),
+    })
+}
+
+fn decode_transaction_receipt(
+    value: &serde_json::Value,
+) -> Result<TransactionReceipt, EthRpcError> {
+    Ok(TransactionReceipt {
+        transaction_hash: decode_string(field(value, "transactionHash")?)?,
+        transaction_index: decode_u64(field(value, "transactionIndex")?)?,
+        block_hash: decode_string(field(value, "blockHash")?)?,
+        block_number: decode_u64(field(value, "blockNumber")?)?,
+        from: decode_string(field(value, "from")?)?,
+        to: decode_opt(value, "to", decode_string)?,
+        contract_address: decode_opt(value, "contractAddress", decode_string)?,
+        cumulative_gas_used: decode_nat(field(value, "cumulativeGasUsed")?)?,
+        gas_used: decode_nat(field(value, "gasUsed")?)?,
+        effective_gas_price: decode_opt(value, "effectiveGasPrice", decode_nat)?,
+        status: decode_opt(value, "status", decode_u64)?,
+        logs: decode_vec(field(value, "logs")?, decode_log_entry)?,
+    })
+}
+
+fn decode_fee_history(value: &serde_json::Value) -> Result<FeeHistory, EthRpcError> {
+    Ok(FeeHistory {
+        oldest_block: decode_u64(field(value, "oldestBlock")?)?,
+        base_fee_per_gas: decode_vec(field(value, "baseFeePerGas")?, decode_nat)?,
+        gas_used_ratio: decode_vec(field(value, "gasUsedRatio")?, |v| {
+            v.as_f64().ok_or_else(|| invalid_response(v))
+        })?,
+        reward: match value.get("reward") {
+            Some(reward) if !reward.is_null() => {
+                decode_vec(reward, |rewards| decode_vec(rewards, decode_nat))?
+            }
+            _ => vec![],
+        },
+    })
+}
+
+fn field<'a>(
+    value: &'a serde_json::Value,
+    name: &str,
+) -> Result<&'a serde_json::Value, EthRpcError> {
+    value
+        .get(name)
+        .ok_or_else(|| EthRpcError::ResponseParseError(format!("missing field {}", name)))
+}
+
+fn invalid_response(value: &serde_json::Value) -> EthRpcError {
+    EthRpcError::ResponseParseError(format!("unexpected value {}", value))
+}
+
+/// Decodes an optional field: missing and null are both None.
+fn decode_opt<T>(
+    value: &serde_json::Value,
+    name: &str,
+    decode: fn(&serde_json::Value) -> Result<T, EthRpcError>,
+) -> Result<Option<T>, EthRpcError> {
+    match value.get(name) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(v) => decode(v).map(Some),
+    }
+}
+
+fn decode_vec<T>(
+    value: &serde_json::Value,
+    decode: impl Fn(&serde_json::Value) -> Result<T, EthRpcError>,
+) -> Result<Vec<T>, EthRpcError> {
+    value
+        .as_array()
+        .ok_or_else(|| invalid_response(value))?
+        .iter()
+        .map(decode)
+        .collect()
+}
+
+fn decode_string(value: &serde_json::Value) -> Result<String, EthRpcError> {
+    value
+        .as_str()
+        .map(|s| s.to_string())
+        .ok_or_else(|| invalid_response(value))
+}
+
+/// Decodes a hex encoded quantity, e.g. "0x1b4".
+fn decode_u64(value: &serde_json::Value) -> Result<u64, EthRpcError> {
+    value
+        .as_str()
+        .and_then(|s| s.strip_prefix("0x"))
+        .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+        .ok_or_else(|| invalid_response(value))
+}
+
+fn decode_nat(value: &serde_json::Value) -> Result<Nat, EthRpcError> {
+    value
+        .as_str()
+        .and_then(|s| s.strip_prefix("0x"))
+        .and_then(|hex| num::BigUint::parse_bytes(hex.as_bytes(), 16))
+        .map(Nat)
+        .ok_or_else(|| invalid_response(value))
+}
+
+fn service_url_allowed(url: &url::Url) -> bool {
+    SERVICE_HOSTS_ALLOWLIST.with(|a| {
+        a.borrow().iter().any(|(k, _)| {
+            ServiceHost::parse(&k.0)
+                .map(|service_host| service_host.matches(url))
+                .unwrap_or(false)
+        })
+    })
+}
+
+fn check_service_url(
+    service_url: &str,
+    service_hosts: Option<&[ServiceHost]>,
+) -> Result<(), EthRpcError> {
+    let parsed_url = url::Url::parse(service_url).or(Err(EthRpcError::ServiceUrlParseError))?;
+    if parsed_url.host_str().is_none() {
+        return Err(EthRpcError::ServiceUrlHostMissing);
+    }
+    let allowed = match service_hosts {
+        Some(service_hosts) => service_hosts.iter().any(|s| s.matches(&parsed_url)),
+        None => service_url_allowed(&parsed_url),
+    };
+    if !allowed {
+        return Err(EthRpcError::ServiceUrlHostNotAllowed);
+    }
+    Ok(())
+}
+
+fn json_rpc_cost_breakdown(
+    pricing: &Pricing,
+    json_rpc_payload: &str,
+    service_url: &str,
+    request_header_bytes: u64,
+    max_response_bytes: u64,
+    provider_fee: u128,
+) -> CostBreakdown {
+    let n = pricing.subnet_size as u128;
+    let ingress_bytes =
         (json_rpc_payload.len() + service_url.len()) as u128 + INGRESS_OVERHEAD_BYTES;
-    INGRESS_MESSAGE_RECEIVED_COST
-        + INGRESS_MESSAGE_BYTE_RECEIVED_COST * ingress_bytes
-        + HTTP_OUTCALL_REQUEST_COST
-        + HTTP_OUTCALL_BYTE_RECEIEVED_COST * (ingress_bytes + max_response_bytes as u128)
+    let ingress = (pricing.ingress_message_received_cost as u128
+        + pricing.ingress_message_byte_received_cost as u128 * ingress_bytes)
+        * n
+        / INGRESS_REFERENCE_SUBNET_SIZE;
+    let outcall_base = (pricing.http_outcall_request_base_cost as u128
+        + pricing.http_outcall_request_per_node_cost as u128 * n)
+        * n;
+    // The provider request headers are part of the outcall request but not of the ingress message.
+    let request_bytes = ingress_bytes + request_header_bytes as u128;
+    let outcall_bytes = pricing.http_outcall_request_byte_cost as u128 * n * request_bytes
+        + pricing.http_outcall_response_byte_cost as u128 * n * max_response_bytes as u128;
+    CostBreakdown {
+        ingress,
+        outcall_base,
+        outcall_bytes,
+        provider_fee,
+        total: ingress + outcall_base + outcall_bytes + provider_fee,
+    }
+}
+
+/// A conservative bound of the size of the raw response given the size of the transformed body.
+fn raw_response_bytes_bound(body_bytes: u64) -> u64 {
+    body_bytes
+        .saturating_mul(RAW_RESPONSE_SIZE_FACTOR)
+        .saturating_add(RAW_RESPONSE_HEADER_BYTES)
+}
+
+/// The outcall cost of the response bytes reserved but not received.
+fn unused_response_bytes_cost(
+    pricing: &Pricing,
+    max_response_bytes: u64,
+    response_bytes: u64,
+) -> u128 {
+    pricing.http_outcall_response_byte_cost as u128
+        * pricing.subnet_size as u128
+        * max_response_bytes.saturating_sub(response_bytes) as u128
+}
+
+/// Returns the cycles charged by json_rpc_request().
+#[ic_cdk::query]
+#[candid_method(query)]
+fn estimate_request_cost(
+    json_rpc_payload: String,
+    service_url: String,
+    max_response_bytes: u64,
+) -> CostBreakdown {
+    let max_response_bytes = match max_response_bytes {
+        0 => auto_max_response_bytes(&json_rpc_payload, None, &service_url),
+        max_response_bytes => max_response_bytes,
+    };
+    json_rpc_cost_breakdown(
+        &get_pricing(),
+        &json_rpc_payload,
+        &service_url,
+        0,
+        max_response_bytes,
+        0,
+    )
+}
+
+/// Returns the cycles charged by json_rpc_provider_request().
+#[ic_cdk::query]
+#[candid_method(query)]
+fn estimate_provider_request_cost(
+    json_rpc_payload: String,
+    provider_id: u64,
+    max_response_bytes: u64,
+) -> Result<CostBreakdown, EthRpcError> {
+    let provider = PROVIDERS
+        .with(|p| p.borrow().get(&provider_id))
+        .ok_or(EthRpcError::ProviderNotFound)?;
+    let service_url = provider.url();
+    let max_response_bytes = match max_response_bytes {
+        0 => auto_max_response_bytes(&json_rpc_payload, Some(provider_id), &service_url),
+        max_response_bytes => max_response_bytes,
+    };
+    let request_header_bytes = provider.request_header_bytes();
+    let provider_fee = json_rpc_provider_cycles_cost(
+        json_rpc_payload.len() as u64 + request_header_bytes,
+        max_response_bytes,
+        provider.cycles_per_call,
+        provider.cycles_per_message_byte,
+    );
+    Ok(json_rpc_cost_breakdown(
+        &get_pricing(),
+        &json_rpc_payload,
+        &service_url,
+        request_header_bytes,
+        max_response_bytes,
+        provider_fee,
+    ))
+}
+
+fn json_rpc_provider_cycles_cost(
+    request_bytes: u64,
+    response_bytes: u64,
+    provider_cycles_per_call: u64,
+    provider_cycles_per_message_byte: u64,
+) -> u128 {
+    provider_cycles_per_call as u128
+        + provider_cycles_per_message_byte as u128
+            * (request_bytes as u128 + response_bytes as u128)
+}
+
+/// Credits the provider with the fee for the actual request and response and returns the rest
+/// of the `provider_cost` charged. There is no fee if the outcall failed (`response_bytes` is
+/// None).
+fn settle_provider_fee(
+    provider: &Provider,
+    request_bytes: u64,
+    response_bytes: Option<u64>,
+    provider_cost: u128,
+) -> u128 {
+    let fee = match response_bytes {
+        Some(response_bytes) => json_rpc_provider_cycles_cost(
+            request_bytes,
+            response_bytes,
+            provider.cycles_per_call,
+            provider.cycles_per_message_byte,
+        )
+        .min(provider_cost),
+        None => 0,
+    };
+    PROVIDERS.with(|p| {
+        let mut p = p.borrow_mut();
+        // The provider may have been unregistered during the outcall.
+        if let Some(mut provider) = p.get(&provider.provider_id) {
+            provider.cycles_owed += fee;
+            p.insert(provider.provider_id, provider)
+                .expect("unable to update Provider");
+        }
+    });
+    provider_cost - fee
+}
+
+// How a request is paid for.
+enum Payment {
+    // Auth::FreeRpc.
+    Free,
+    // From the cycles attached to the message. They are held until the outcall has completed and
+    // only the actual charge is then accepted, so the rest is returned to the caller with the
+    // reply.
+    Attached { held: u128 },
+    // From the prepaid balance, debited before the outcall and recorded in ACCOUNT_USAGE.
+    Balance { usage_index: u64 },
+}
+
+// The attached cycles held by the requests in progress of a call, see Payment::Attached.
+// Each call has its own as the attached cycles belong to the call.
+#[derive(Default)]
+struct HeldCycles(std::cell::Cell<u128>);
+
+impl HeldCycles {
+    fn get(&self) -> u128 {
+        self.0.get()
+    }
+
+    fn hold(&self, cycles: u128) {
+        self.0.set(self.0.get() + cycles);
+    }
+
+    fn release(&self, cycles: u128) {
+        self.0.set(self.0.get().saturating_sub(cycles));
+    }
+}
+
+/// Charges the payment less the `refund`, which is returned the way the request was paid.
+fn settle_payment(payment: Payment, refund: u128, held_cycles: &HeldCycles) {
+    let caller = ic_cdk::caller();
+    match payment {
+        Payment::Free => (),
+        Payment::Attached { held } => {
+            held_cycles.release(held);
+            let refund = refund.min(held);
+            add_metric!(json_rpc_request_cycles_refunded, refund);
+            let charge = held - refund;
+            let accepted = ic_cdk::api::call::msg_cycles_accept128(charge);
+            if accepted < charge {
+                log!(ERROR, "accepted {} of {} cycles", accepted, charge);
+            }
+        }
+        Payment::Balance { usage_index } => {
+            if refund > 0 {
+                credit_balance(caller, refund);
+                add_metric!(json_rpc_request_cycles_refunded, refund);
+                add_metric!(account_cycles_refunded, refund);
+                // Record the net charge.
+                let key = UsageKey {
+                    caller,
+                    index: usage_index,
+                };
+                ACCOUNT_USAGE.with(|u| {
+                    let mut u = u.borrow_mut();
+                    if let Some(mut usage) = u.get(&key) {
+                        usage.cycles = usage.cycles.saturating_sub(refund);
+                        u.insert(key, usage).expect("unable to record Usage");
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_pricing() -> Pricing {
+    PRICING.with(|p| p.borrow().get().clone())
+}
+
+#[ic_cdk::update(guard = "is_authorized")]
+#[candid_method]
+fn set_pricing(pricing: Pricing) {
+    if let Err(e) = pricing.validate() {
+        ic_cdk::trap(&e);
+    }
+    PRICING.with(|p| p.borrow_mut().set(pricing).expect("unable to set Pricing"));
+}
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_providers() -> Vec<RegisteredProvider> {
+    PROVIDERS.with(|p| {
+        p.borrow()
+            .iter()
+            .map(|(_, e)| registered_provider(e, ic_cdk::api::time()))
+            .collect::<Vec<RegisteredProvider>>()
+    })
+}
+
+/// Returns up to `limit` providers matching the filter in order of provider id, starting at
+/// the provider id `cursor`.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_providers_filtered(filter: ProviderFilter, cursor: Option<u64>, limit: u32) -> ProviderPage {
+    provider_page(&filter, cursor, limit, ic_cdk::api::time())
+}
+
+fn provider_page(
+    filter: &ProviderFilter,
+    cursor: Option<u64>,
+    limit: u32,
+    time: u64,
+) -> ProviderPage {
+    let limit = match limit {
+        0 => GET_PROVIDERS_MAX_LIMIT,
+        limit => limit.min(GET_PROVIDERS_MAX_LIMIT),
+    } as usize;
+    let mut providers: Vec<Provider> = PROVIDERS.with(|p| {
+        p.borrow()
+            .range(cursor.unwrap_or(0)..)
+            .map(|(_, provider)| provider)
+            .filter(|provider| provider_matches(provider, filter, time))
+            .take(limit + 1)
+            .collect()
+    });
+    let next_cursor = if providers.len() > limit {
+        providers.pop().map(|provider| provider.provider_id)
+    } else {
+        None
+    };
+    ProviderPage {
+        providers: providers
+            .into_iter()
+            .map(|provider| registered_provider(provider, time))
+            .collect(),
+        next_cursor,
+    }
+}
+
+fn provider_matches(provider: &Provider, filter: &ProviderFilter, time: u64) -> bool {
+    if filter
+        .chain_id
+        .map_or(false, |chain_id| chain_id != provider.chain_id)
+        || filter.owner.map_or(false, |owner| owner != provider.owner)
+    {
+        return false;
+    }
+    if let Some(host) = &filter.host {
+        let provider_host = url::Url::parse(&provider.service_url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_string()));
+        if provider_host.as_deref() != Some(host.to_lowercase().as_str()) {
+            return false;
+        }
+    }
+    filter.active.map_or(true, |active| {
+        active == (provider.status(time) == ProviderStatus::Active)
+    })
+}
+
+fn registered_provider(provider: Provider, time: u64) -> RegisteredProvider {
+    RegisteredProvider {
+        provider_id: provider.provider_id,
+        owner: provider.owner,
+        chain_id: provider.chain_id,
+        status: provider.status(time),
+        service_url: provider.service_url,
+        cycles_per_call: provider.cycles_per_call,
+        cycles_per_message_byte: provider.cycles_per_message_byte,
+        maintenance: provider.maintenance,
+    }
+}
+
+#[ic_cdk::update(guard = "is_authorized_register_provider")]
+#[candid_method]
+fn register_provider(provider: RegisterProvider) -> Result<u64, EthRpcError> {
+    let mut provider = Provider {
+        provider_id: 0,
+        owner: ic_cdk::caller(),
+        chain_id: provider.chain_id,
+        service_url: provider.service_url,
+        api_key: provider.api_key,
+        cycles_per_call: provider.cycles_per_call,
+        cycles_per_message_byte: provider.cycles_per_message_byte,
+        cycles_owed: 0,
+        active: Some(true),
+        maintenance: None,
+        headers: provider.headers,
+    };
+    provider.validate()?;
+    provider.provider_id = METADATA.with(|m| {
+        let mut metadata = m.borrow().get().clone();
+        metadata.next_provider_id += 1;
+        m.borrow_mut().set(metadata.clone()).unwrap();
+        metadata.next_provider_id - 1
+    });
+    let provider_id = provider.provider_id;
+    PROVIDERS.with(|p| p.borrow_mut().insert(provider_id, provider));
+    Ok(provider_id)
+}
+
+#[ic_cdk::update(guard = "is_authorized_register_provider")]
+#[candid_method]
+fn update_provider(provider_id: u64, update: UpdateProvider) -> Result<(), EthRpcError> {
+    let mut provider = owned_provider(provider_id)?;
+    if let Some(service_url) = update.service_url {
+        provider.service_url = service_url;
+    }
+    if let Some(api_key) = update.api_key {
+        provider.api_key = api_key;
+    }
+    if let Some(cycles_per_call) = update.cycles_per_call {
+        provider.cycles_per_call = cycles_per_call;
+    }
+    if let Some(cycles_per_message_byte) = update.cycles_per_message_byte {
+        provider.cycles_per_message_byte = cycles_per_message_byte;
+    }
+    if let Some(headers) = update.headers {
+        provider.headers = Some(headers);
+    }
+    provider.validate()?;
+    PROVIDERS.with(|p| p.borrow_mut().insert(provider_id, provider));
+    Ok(())
+}
+
+/// Disabled providers are not used until they are enabled again.
+#[ic_cdk::update(guard = "is_authorized_register_provider")]
+#[candid_method]
+fn set_provider_active(provider_id: u64, active: bool) -> Result<(), EthRpcError> {
+    let mut provider = owned_provider(provider_id)?;
+    provider.active = Some(active);
+    PROVIDERS.with(|p| p.borrow_mut().insert(provider_id, provider));
+    Ok(())
+}
+
+/// Providers are not used during the maintenance window, if any.
+#[ic_cdk::update(guard = "is_authorized_register_provider")]
+#[candid_method]
+fn set_provider_maintenance(
+    provider_id: u64,
+    maintenance: Option<MaintenanceWindow>,
+) -> Result<(), EthRpcError> {
+    if let Some(window) = &maintenance {
+        if window.start >= window.end {
+            return Err(EthRpcError::InvalidProvider(
+                "maintenance start must be before end".to_string(),
+            ));
+        }
+    }
+    let mut provider = owned_provider(provider_id)?;
+    provider.maintenance = maintenance;
+    PROVIDERS.with(|p| p.borrow_mut().insert(provider_id, provider));
+    Ok(())
+}
+
+/// Returns the provider if the caller is its owner or an admin.
+fn owned_provider(provider_id: u64) -> Result<Provider, EthRpcError> {
+    let provider = PROVIDERS.with(|p| p.borrow().get(&provider_id));
+    let provider = provider.ok_or(EthRpcError::ProviderNotFound)?;
+    if provider.owner != ic_cdk::caller() && !authorized(Auth::Admin) {
+        return Err(EthRpcError::NoPermission);
+    }
+    Ok(provider)
+}
+
+#[ic_cdk::update(guard = "is_authorized_register_provider")]
+#[candid_method]
+fn unregister_provider(provider_id: u64) {
+    PROVIDERS.with(|p| {
+        if let Some(provider) = p.borrow().get(&provider_id) {
+            if provider.owner == ic_cdk::caller() || authorized(Auth::Admin) {
+                p.borrow_mut().remove(&provider_id);
+            } else {
+                ic_cdk::trap("Not authorized");
+            }
+        }
+    });
+}
+
+#[ic_cdk::query(guard = "is_authorized_register_provider")]
+#[candid_method(query)]
+fn get_owed_cycles(provider_id: u64) -> u128 {
+    let provider = PROVIDERS.with(|p| {
+        p.borrow()
+            .get(&provider_id)
+            .ok_or(EthRpcError::ProviderNotFound)
+    });
+    let provider = provider.expect("Provider not found");
+    if ic_cdk::caller() != provider.owner {
+        ic_cdk::trap("Not owner");
+    }
+    provider.cycles_owed
+}
+
+#[derive(CandidType)]
+struct DepositCyclesArgs {
+    canister_id: Principal,
+}
+
+#[ic_cdk::update(guard = "is_authorized_register_provider")]
+#[candid_method]
+async fn withdraw_owed_cycles(provider_id: u64, canister_id: Principal) {
+    let provider = PROVIDERS.with(|p| {
+        p.borrow()
+            .get(&provider_id)
+            .ok_or(EthRpcError::ProviderNotFound)
+    });
+    let mut provider = provider.expect("Provider not found");
+    if ic_cdk::caller() != provider.owner {
+        ic_cdk::trap("Not owner");
+    }
+    let amount = provider.cycles_owed;
+    if amount < MINIMUM_WITHDRAWAL_CYCLES {
+        ic_cdk::trap("Too few cycles to withdraw");
+    }
+    PROVIDERS.with(|p| {
+        provider.cycles_owed = 0;
+        p.borrow_mut().insert(provider_id, provider)
+    });
+    match ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "deposit_cycles",
+        (DepositCyclesArgs { canister_id },),
+        amount,
+    )
+    .await
+    {
+        Ok(()) => (),
+        // Note: cycles are not refunded (would that be exploitable?).
+        Err(e) => ic_cdk::trap(&format!("failed to deposit_cycles: {:?}", e)),
+    };
+}
+
+#[ic_cdk::update]
+#[candid_method]
+fn deposit_cycles() -> u128 {
+    let amount =
+        ic_cdk::api::call::msg_cycles_accept128(ic_cdk::api::call::msg_cycles_available128());
+    add_metric!(account_cycles_deposited, amount);
+    credit_balance(ic_cdk::caller(), amount)
+}
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_balance() -> u128 {
+    get_account(ic_cdk::caller()).balance
+}
+
+#[ic_cdk::update]
+#[candid_method]
+async fn withdraw_balance(amount: u128, canister_id: Principal) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if amount < MINIMUM_WITHDRAWAL_CYCLES {
+        return Err("Too few cycles to withdraw".to_string());
+    }
+    let mut account = get_account(caller);
+    if account.balance < amount {
+        return Err("Insufficient balance".to_string());
+    }
+    account.balance -= amount;
+    ACCOUNTS.with(|a| a.borrow_mut().insert(PrincipalStorable(caller), account));
+    match ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "deposit_cycles",
+        (DepositCyclesArgs { canister_id },),
+        amount,
+    )
+    .await
+    {
+        Ok(()) => {
+            add_metric!(account_cycles_withdrawn, amount);
+            Ok(())
+        }
+        Err((code, message)) => {
+            // The cycles were refunded to this canister so return them to the balance.
+            credit_balance(caller, amount);
+            log!(INFO, "failed to withdraw balance: {:?} {}", code, message);
+            Err(format!("failed to deposit cycles: {:?} {}", code, message))
+        }
+    }
+}
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_billing_mode() -> BillingMode {
+    get_account(ic_cdk::caller())
+        .billing_mode
+        .unwrap_or_default()
+}
+
+/// Sets the billing mode of a principal. With BillingMode::ActualResponseBytes this canister
+/// pays for the response bytes which were reserved but not received.
+#[ic_cdk::update(guard = "is_authorized")]
+#[candid_method]
+fn set_billing_mode(principal: Principal, billing_mode: BillingMode) {
+    let mut account = get_account(principal);
+    account.billing_mode = Some(billing_mode);
+    ACCOUNTS.with(|a| a.borrow_mut().insert(PrincipalStorable(principal), account));
+}
+
+/// Returns the most recent requests paid for from the balance of the caller, oldest first.
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_usage_history() -> Vec<Usage> {
+    usage_history(ic_cdk::caller())
+}
+
+fn usage_history(caller: Principal) -> Vec<Usage> {
+    ACCOUNT_USAGE.with(|u| {
+        u.borrow()
+            .range(
+                UsageKey { caller, index: 0 }..=UsageKey {
+                    caller,
+                    index: u64::MAX,
+                },
+            )
+            .map(|(_, usage)| usage)
+            .collect()
+    })
+}
+
+fn get_account(caller: Principal) -> Account {
+    ACCOUNTS.with(|a| {
+        a.borrow()
+            .get(&PrincipalStorable(caller))
+            .unwrap_or_default()
+    })
+}
+
+/// Adds to the balance of the caller and returns the new balance.
+fn credit_balance(caller: Principal, amount: u128) -> u128 {
+    let mut account = get_account(caller);
+    account.balance += amount;
+    let balance = account.balance;
+    ACCOUNTS.with(|a| a.borrow_mut().insert(PrincipalStorable(caller), account));
+    balance
+}
+
+/// Pays for a request from the balance of the caller and records it in the usage history.
+/// Returns the index of the usage record.
+fn debit_balance(caller: Principal, usage: Usage) -> Result<u64, EthRpcError> {
+    let mut account = get_account(caller);
+    if account.balance < usage.cycles {
+        return Err(EthRpcError::TooFewCycles(format!(
+            "requires {} cycles, balance {} cycles",
+            usage.cycles, account.balance
+        )));
+    }
+    account.balance -= usage.cycles;
+    add_metric!(account_cycles_debited, usage.cycles);
+    let index = account.next_usage_index;
+    account.next_usage_index += 1;
+    ACCOUNTS.with(|a| a.borrow_mut().insert(PrincipalStorable(caller), account));
+    ACCOUNT_USAGE.with(|u| {
+        let mut u = u.borrow_mut();
+        if index >= USAGE_HISTORY_MAX_LEN {
+            u.remove(&UsageKey {
+                caller,
+                index: index - USAGE_HISTORY_MAX_LEN,
+            });
+        }
+        u.insert(UsageKey { caller, index }, usage);
+    });
+    Ok(index)
+}
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_provider_order(chain_id: u64) -> Vec<u64> {
+    PROVIDER_ORDER.with(|o| o.borrow().get(&chain_id).unwrap_or_default().0)
+}
+
+#[ic_cdk::update(guard = "is_authorized")]
+#[candid_method]
+fn set_provider_order(chain_id: u64, provider_ids: Vec<u64>) {
+    if provider_ids.len() > PROVIDER_ORDER_MAX_LEN {
+        ic_cdk::trap("Too many providers");
+    }
+    PROVIDER_ORDER.with(|o| {
+        if provider_ids.is_empty() {
+            o.borrow_mut().remove(&chain_id);
+        } else {
+            o.borrow_mut().insert(chain_id, ProviderOrder(provider_ids));
+        }
+    });
+}
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_service_hosts() -> Vec<ServiceHost> {
+    SERVICE_HOSTS_ALLOWLIST.with(|a| {
+        a.borrow()
+            .iter()
+            .filter_map(|(k, _)| ServiceHost::parse(&k.0).ok())
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "is_authorized")]
+#[candid_method]
+fn add_service_host(service_host: ServiceHost) {
+    let key = match service_host.key() {
+        Ok(key) => key,
+        Err(e) => ic_cdk::trap(&e),
+    };
+    log!(INFO, "add service host {}", key);
+    SERVICE_HOSTS_ALLOWLIST.with(|a| a.borrow_mut().insert(StringStorable(key), ()));
+}
+
+#[ic_cdk::update(guard = "is_authorized")]
+#[candid_method]
+fn remove_service_host(service_host: ServiceHost) {
+    let key = service_host.to_string();
+    log!(INFO, "remove service host {}", key);
+    SERVICE_HOSTS_ALLOWLIST.with(|a| a.borrow_mut().remove(&StringStorable(key)));
+}
+
+#[ic_cdk_macros::query(name = "transform")]
+fn transform(args: TransformArgs) -> HttpResponse {
+    // An empty context only strips the headers.
+    let body = if args.context.is_empty() {
+        args.response.body
+    } else {
+        match Decode!(&args.context, TransformOptions) {
+            Ok(options) => transform_body(&options, args.response.body),
+            Err(_) => args.response.body,
+        }
+    };
+    HttpResponse {
+        status: args.response.status,
+        body,
+        // Strip headers as they contain the Date which is not necessarily the same
+        // and will prevent consensus on the result.
+        headers: Vec::<HttpHeader>::new(),
+    }
+}
+
+/// The caller's transform options, the named preset or, by default, the preset for the method
+/// of the request. Responses to methods without a preset are canonicalized.
+fn transform_options(
+    json_rpc_payload: &str,
+    transform: Option<Transform>,
+) -> Result<TransformOptions, EthRpcError> {
+    match transform {
+        None => Ok(json_rpc_method(json_rpc_payload)
+            .and_then(|method| transform_preset(&method))
+            .unwrap_or(TransformOptions {
+                canonicalize: true,
+                ..TransformOptions::default()
+            })),
+        Some(Transform::Preset(method)) => transform_preset(&method)
+            .ok_or_else(|| EthRpcError::InvalidTransform(format!("unknown preset {}", method))),
+        Some(Transform::Options(options)) => {
+            for field in &options.set_fields {
+                if serde_json::from_str::<serde_json::Value>(&field.value).is_err() {
+                    return Err(EthRpcError::InvalidTransform(format!(
+                        "value of {} is not JSON",
+                        field.path
+                    )));
+                }
+            }
+            Ok(options)
+        }
+    }
+}
+
+/// Canonicalizes responses and drops the fields of the preset for the method.
+fn transform_preset(method: &str) -> Option<TransformOptions> {
+    TRANSFORM_PRESETS
+        .iter()
+        .find(|(m, _)| *m == method)
+        .map(|(_, fields)| TransformOptions {
+            canonicalize: true,
+            drop_fields: fields.iter().map(|f| f.to_string()).collect(),
+            set_fields: vec![],
+        })
+}
+
+fn json_rpc_method(json_rpc_payload: &str) -> Option<String> {
+    let payload = serde_json::from_str::<serde_json::Value>(json_rpc_payload).ok()?;
+    Some(payload.get("method")?.as_str()?.to_string())
+}
+
+fn transform_body(options: &TransformOptions, body: Vec<u8>) -> Vec<u8> {
+    let mut value = match serde_json::from_slice::<serde_json::Value>(&body) {
+        Ok(value) => value,
+        Err(_) => return body,
+    };
+    for field in &options.drop_fields {
+        drop_json_field(&mut value, &field.split('.').collect::<Vec<_>>());
+    }
+    for field in &options.set_fields {
+        if let Ok(new_value) = serde_json::from_str::<serde_json::Value>(&field.value) {
+            set_json_field(
+                &mut value,
+                &field.path.split('.').collect::<Vec<_>>(),
+                &new_value,
+            );
+        }
+    }
+    // Serializing sorts the keys, see canonical_json().
+    if !options.canonicalize && options.drop_fields.is_empty() && options.set_fields.is_empty() {
+        return body;
+    }
+    serde_json::to_vec(&value).unwrap_or(body)
+}
+
+fn drop_json_field(value: &mut serde_json::Value, path: &[&str]) {
+    match value {
+        serde_json::Value::Array(values) => {
+            for value in values {
+                drop_json_field(value, path);
+            }
+        }
+        serde_json::Value::Object(map) => match path {
+            [name] => {
+                map.remove(*name);
+            }
+            [name, rest @ ..] => {
+                if let Some(value) = map.get_mut(*name) {
+                    drop_json_field(value, rest);
+                }
+            }
+            [] => (),
+        },
+        _ => (),
+    }
+}
+
+/// Sets the field at `path` to `new_value` where it is present, in each element of arrays.
+fn set_json_field(value: &mut serde_json::Value, path: &[&str], new_value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Array(values) => {
+            for value in values {
+                set_json_field(value, path, new_value);
+            }
+        }
+        serde_json::Value::Object(map) => match path {
+            [name] => {
+                if let Some(value) = map.get_mut(*name) {
+                    *value = new_value.clone();
+                }
+            }
+            [name, rest @ ..] => {
+                if let Some(value) = map.get_mut(*name) {
+                    set_json_field(value, rest, new_value);
+                }
+            }
+            [] => (),
+        },
+        _ => (),
+    }
 }
 
-fn json_rpc_provider_cycles_cost(
-    json_rpc_payload: &str,
-    provider_cycles_per_call: u64,
-    provider_cycles_per_message_byte: u64,
-) -> u128 {
-    provider_cycles_per_call as u128
-        + provider_cycles_per_message_byte as u128
-        + json_rpc_payload.len() as u128
+#[ic_cdk_macros::init]
+fn init(args: Option<InitArgs>) {
+    initialize();
+    if let Some(args) = args {
+        apply_config(args);
+    }
 }
 
-#[ic_cdk::query]
-#[candid_method(query)]
-fn get_providers() -> Vec<RegisteredProvider> {
-    PROVIDERS.with(|p| {
-        p.borrow()
-            .iter()
-            .map(|(_, e)| RegisteredProvider {
-                provider_id: e.provider_id,
-                owner: e.owner,
-                chain_id: e.chain_id,
-                service_url: e.service_url,
-                cycles_per_call: e.cycles_per_call,
-                cycles_per_message_byte: e.cycles_per_message_byte,
-            })
-            .collect::<Vec<RegisteredProvider>>()
-    })
+#[ic_cdk_macros::post_upgrade]
+fn post_upgrade(args: Option<UpgradeArgs>) {
+    migrate_providers();
+    migrate_auth();
+    initialize();
+    if let Some(args) = args {
+        apply_config(args);
+    }
 }
 
-#[ic_cdk::update(guard = "is_authorized_register_provider")]
-#[candid_method]
-fn register_provider(provider: RegisterProvider) {
-    let provider_id = METADATA.with(|m| {
-        let mut metadata = m.borrow().get().clone();
-        metadata.next_provider_id += 1;
-        m.borrow_mut().set(metadata.clone()).unwrap();
-        metadata.next_provider_id - 1
-    });
-    PROVIDERS.with(|p| {
-        p.borrow_mut().insert(
-            provider_id,
-            Provider {
-                provider_id,
-                owner: ic_cdk::caller(),
-                chain_id: provider.chain_id,
-                service_url: provider.service_url,
-                api_key: provider.api_key,
-                cycles_per_call: provider.cycles_per_call,
-                cycles_per_message_byte: provider.cycles_per_message_byte,
-                cycles_owed: 0,
-            },
-        )
+// Traps (failing the install or upgrade) if any of the configuration is invalid.
+fn apply_config(args: InitArgs) {
+    let service_hosts = args.service_hosts.map(|service_hosts| {
+        service_hosts
+            .iter()
+            .map(|service_host| match service_host.key() {
+                Ok(key) => key,
+                Err(e) => ic_cdk::trap(&e),
+            })
+            .collect::<Vec<String>>()
     });
+    if let Some(pricing) = &args.pricing {
+        if let Err(e) = pricing.validate() {
+            ic_cdk::trap(&e);
+        }
+    }
+    if let Some(rpc_access) = args.rpc_access {
+        set_rpc_access(rpc_access);
+    }
+    if let Some(principals) = args.rpc_denylist {
+        RPC_DENYLIST.with(|d| {
+            let mut denylist = d.borrow_mut();
+            let keys: Vec<PrincipalStorable> = denylist.iter().map(|(k, _)| k).collect();
+            for key in keys {
+                denylist.remove(&key);
+            }
+            for principal in principals {
+                denylist.insert(PrincipalStorable(principal), ());
+            }
+        });
+    }
+    for (principals, auth) in [
+        (args.rpc_allowlist, Auth::Rpc),
+        (args.register_provider_allowlist, Auth::RegisterProvider),
+        (args.free_rpc_allowlist, Auth::FreeRpc),
+        (args.admins, Auth::Admin),
+    ] {
+        if let Some(principals) = principals {
+            set_authorized(&principals, auth);
+        }
+    }
+    if let Some(service_hosts) = service_hosts {
+        SERVICE_HOSTS_ALLOWLIST.with(|a| {
+            let mut allowlist = a.borrow_mut();
+            let keys: Vec<StringStorable> = allowlist.iter().map(|(k, _)| k).collect();
+            for key in keys {
+                allowlist.remove(&key);
+            }
+            for key in service_hosts {
+                allowlist.insert(StringStorable(key), ());
+            }
+        });
+    }
+    if let Some(pricing) = args.pricing {
+        PRICING.with(|p| p.borrow_mut().set(pricing).expect("unable to set Pricing"));
+    }
 }
 
-#[ic_cdk::update(guard = "is_authorized_register_provider")]
-#[candid_method]
-fn unregister_provider(provider_id: u64) {
-    PROVIDERS.with(|p| {
-        if let Some(provider) = p.borrow().get(&provider_id) {
-            if provider.owner == ic_cdk::caller() || authorized(Auth::Admin) {
-                p.borrow_mut().remove(&provider_id);
-            } else {
-                ic_cdk::trap("Not authorized");
+fn parse_config_bundle(text: &str) -> Result<ConfigBundle, Vec<String>> {
+    let bundle: ConfigBundle = json5::from_str(text).map_err(|e| match e {
+        json5::Error::Message {
+            msg,
+            location: Some(location),
+        } => vec![format!(
+            "line {}, column {}: {}",
+            location.line, location.column, msg
+        )],
+        json5::Error::Message {
+            msg,
+            location: None,
+        } => vec![msg],
+    })?;
+    // Errors are reported at the key of the field, e.g. the provider_id of a provider.
+    let located =
+        |key: &str, index: usize, error: String| match json5_key_locations(text, key).get(index) {
+            Some((line, column)) => format!("line {}, column {}: {}", line, column, error),
+            None => error,
+        };
+    let mut errors = Vec::new();
+    if let Some(Err(e)) = bundle.pricing.as_ref().map(Pricing::validate) {
+        errors.push(located("pricing", 0, format!("pricing: {}", e)));
+    }
+    // Providers are checked against the service hosts of the document, if any, as these
+    // replace the current ones.
+    let service_hosts: Option<Vec<ServiceHost>> = bundle
+        .service_hosts
+        .as_ref()
+        .map(|service_hosts| service_hosts.iter().map(|s| s.0.clone()).collect());
+    let service_hosts = service_hosts.as_deref();
+    if let Some(service_hosts) = service_hosts {
+        let configured = |provider_id| {
+            bundle
+                .providers
+                .iter()
+                .flatten()
+                .any(|config| config.provider_id == provider_id)
+        };
+        for (provider_id, provider) in PROVIDERS.with(|p| p.borrow().iter().collect::<Vec<_>>()) {
+            if configured(provider_id) {
+                continue;
+            }
+            if let Err(e) = check_service_url(&provider.url(), Some(service_hosts)) {
+                errors.push(located(
+                    "service_hosts",
+                    0,
+                    format!("service_hosts: provider {}: {:?}", provider_id, e),
+                ));
             }
         }
-    });
+    }
+    for (i, config) in bundle.providers.iter().flatten().enumerate() {
+        let error = match PROVIDERS.with(|p| p.borrow().get(&config.provider_id)) {
+            None => Some(format!("provider {} not found", config.provider_id)),
+            Some(provider) if provider.owner != config.owner.0 => {
+                Some("owner can not be changed".to_string())
+            }
+            Some(provider) if provider.chain_id != config.chain_id => {
+                Some("chain_id can not be changed".to_string())
+            }
+            Some(provider) => updated_provider(provider, config)
+                .validate_with(service_hosts)
+                .err()
+                .map(|e| format!("{:?}", e)),
+        };
+        if let Some(e) = error {
+            errors.push(located(
+                "provider_id",
+                i,
+                format!("providers[{}]: {}", i, e),
+            ));
+        }
+    }
+    if errors.is_empty() {
+        Ok(bundle)
+    } else {
+        Err(errors)
+    }
 }
 
-#[ic_cdk::query(guard = "is_authorized_register_provider")]
-#[candid_method(query)]
-fn get_owed_cycles(provider_id: u64) -> u128 {
-    let provider = PROVIDERS.with(|p| {
-        p.borrow()
-            .get(&provider_id)
-            .ok_or(EthRpcError::ProviderNotFound)
-    });
-    let provider = provider.expect("Provider not found");
-    if ic_cdk::caller() != provider.owner {
-        ic_cdk::trap("Not owner");
-    }
-    provider.cycles_owed
+fn updated_provider(mut provider: Provider, config: &ProviderConfig) -> Provider {
+    provider.service_url = config.service_url.clone();
+    provider.cycles_per_call = config.cycles_per_call;
+    provider.cycles_per_message_byte = config.cycles_per_message_byte;
+    provider.active = Some(config.active);
+    provider.maintenance = config.maintenance.clone();
+    provider
 }
 
-#[derive(CandidType)]
-struct DepositCyclesArgs {
-    canister_id: Principal,
+fn to_principals(principals: Option<Vec<PrincipalText>>) -> Option<Vec<Principal>> {
+    principals.map(|principals| principals.into_iter().map(|p| p.0).collect())
 }
 
-#[ic_cdk::update(guard = "is_authorized_register_provider")]
-#[candid_method]
-async fn withdraw_owed_cycles(provider_id: u64, canister_id: Principal) {
-    let provider = PROVIDERS.with(|p| {
-        p.borrow()
-            .get(&provider_id)
-            .ok_or(EthRpcError::ProviderNotFound)
-    });
-    let mut provider = provider.expect("Provider not found");
-    if ic_cdk::caller() != provider.owner {
-        ic_cdk::trap("Not owner");
+/// The line and column (from 1) of each `key` of an object in a JSON5 document, in order.
+/// Comments and strings which are not keys are skipped.
+fn json5_key_locations(text: &str, key: &str) -> Vec<(usize, usize)> {
+    let chars: Vec<char> = text.chars().collect();
+    let followed_by_colon = |mut j: usize| {
+        while chars.get(j).map_or(false, |c| c.is_whitespace()) {
+            j += 1;
+        }
+        chars.get(j) == Some(&':')
+    };
+    let mut locations = Vec::new();
+    let (mut i, mut line, mut column) = (0, 1, 1);
+    while i < chars.len() {
+        // The end of the comment, string, identifier or other character at i.
+        let end = match chars[i] {
+            '/' if chars.get(i + 1) == Some(&'/') => (i..chars.len())
+                .find(|j| chars[*j] == '\n')
+                .unwrap_or(chars.len()),
+            '/' if chars.get(i + 1) == Some(&'*') => (i + 2..chars.len().saturating_sub(1))
+                .find(|j| chars[*j] == '*' && chars[*j + 1] == '/')
+                .map_or(chars.len(), |j| j + 2),
+            quote @ ('"' | '\'') => {
+                let mut j = i + 1;
+                while j < chars.len() && chars[j] != quote {
+                    j += if chars[j] == '\\' { 2 } else { 1 };
+                }
+                let end = (j + 1).min(chars.len());
+                let content: String = chars[i + 1..end - 1].iter().collect();
+                if content == key && followed_by_colon(end) {
+                    locations.push((line, column));
+                }
+                end
+            }
+            c if c.is_alphabetic() || c == '_' || c == '$' => {
+                let end = (i..chars.len())
+                    .find(|j| {
+                        !(chars[*j].is_alphanumeric() || chars[*j] == '_' || chars[*j] == '$')
+                    })
+                    .unwrap_or(chars.len());
+                if chars[i..end].iter().collect::<String>() == key && followed_by_colon(end) {
+                    locations.push((line, column));
+                }
+                end
+            }
+            _ => i + 1,
+        };
+        for c in &chars[i..end] {
+            if *c == '\n' {
+                line += 1;
+                column = 1;
+            } else {
+                column += 1;
+            }
+        }
+        i = end;
     }
-    let amount = provider.cycles_owed;
-    if amount < MINIMUM_WITHDRAWAL_CYCLES {
-        ic_cdk::trap("Too few cycles to withdraw");
+    locations
+}
+
+/// Replaces the configuration set in the JSON5 document. Returns the errors, one per line or
+/// entry, without changing the configuration if the document is not valid.
+#[ic_cdk::update(guard = "is_authorized")]
+#[candid_method]
+fn import_config(text: String) -> Result<(), Vec<String>> {
+    let bundle = parse_config_bundle(&text)?;
+    for config in bundle.providers.iter().flatten() {
+        let provider = PROVIDERS
+            .with(|p| p.borrow().get(&config.provider_id))
+            .unwrap();
+        let provider = updated_provider(provider, config);
+        PROVIDERS.with(|p| p.borrow_mut().insert(config.provider_id, provider));
     }
-    PROVIDERS.with(|p| {
-        provider.cycles_owed = 0;
-        p.borrow_mut().insert(provider_id, provider)});
-    match ic_cdk::api::call::call_with_payment128(
-        Principal::management_canister(),
-        "deposit_cycles",
-        (DepositCyclesArgs { canister_id },),
-        amount,
-    )
-    .await
-    {
-        Ok(()) => (),
-        // Note: cycles are not refunded (would that be exploitable?).
-        Err(e) => ic_cdk::trap(&format!("failed to deposit_cycles: {:?}", e)),
+    apply_config(InitArgs {
+        rpc_access: bundle.rpc_access,
+        rpc_allowlist: to_principals(bundle.rpc_allowlist),
+        rpc_denylist: to_principals(bundle.rpc_denylist),
+        register_provider_allowlist: to_principals(bundle.register_provider_allowlist),
+        free_rpc_allowlist: to_principals(bundle.free_rpc_allowlist),
+        admins: to_principals(bundle.admins),
+        service_hosts: bundle
+            .service_hosts
+            .map(|service_hosts| service_hosts.into_iter().map(|s| s.0).collect()),
+        pricing: bundle.pricing,
+    });
+    log!(INFO, "imported config");
+    Ok(())
+}
+
+/// Returns the configuration, including the providers without their secrets, as a JSON5
+/// document which can be passed to import_config.
+#[ic_cdk::query(guard = "is_authorized")]
+#[candid_method(query)]
+fn export_config() -> String {
+    let config = get_config();
+    let principals = |principals: Option<Vec<Principal>>| {
+        principals.map(|principals| principals.into_iter().map(PrincipalText).collect())
     };
+    let bundle = ConfigBundle {
+        rpc_access: config.rpc_access,
+        rpc_allowlist: principals(config.rpc_allowlist),
+        rpc_denylist: principals(config.rpc_denylist),
+        register_provider_allowlist: principals(config.register_provider_allowlist),
+        free_rpc_allowlist: principals(config.free_rpc_allowlist),
+        admins: principals(config.admins),
+        service_hosts: config
+            .service_hosts
+            .map(|service_hosts| service_hosts.into_iter().map(ServiceHostText).collect()),
+        pricing: config.pricing,
+        providers: Some(PROVIDERS.with(|p| {
+            p.borrow()
+                .iter()
+                .map(|(_, provider)| ProviderConfig {
+                    provider_id: provider.provider_id,
+                    owner: PrincipalText(provider.owner),
+                    chain_id: provider.chain_id,
+                    service_url: provider.service_url,
+                    cycles_per_call: provider.cycles_per_call,
+                    cycles_per_message_byte: provider.cycles_per_message_byte,
+                    active: provider.active != Some(false),
+                    maintenance: provider.maintenance,
+                })
+                .collect()
+        })),
+    };
+    // JSON is valid JSON5.
+    serde_json::to_string_pretty(&bundle).unwrap()
 }
 
-#[ic_cdk_macros::query(name = "transform")]
-fn transform(args: TransformArgs) -> HttpResponse {
-    HttpResponse {
-        status: args.response.status.clone(),
-        body: args.response.body,
-        // Strip headers as they contain the Date which is not necessarily the same
-        // and will prevent consensus on the result.
-        headers: Vec::<HttpHeader>::new(),
+/// Returns the deployment configuration, which can be passed as UpgradeArgs.
+#[ic_cdk::query(guard = "is_authorized")]
+#[candid_method(query)]
+fn get_config() -> InitArgs {
+    InitArgs {
+        rpc_access: Some(get_rpc_access()),
+        rpc_allowlist: Some(get_authorized(Auth::Rpc)),
+        rpc_denylist: Some(get_rpc_denylist()),
+        register_provider_allowlist: Some(get_authorized(Auth::RegisterProvider)),
+        free_rpc_allowlist: Some(get_authorized(Auth::FreeRpc)),
+        admins: Some(get_authorized(Auth::Admin)),
+        service_hosts: Some(get_service_hosts()),
+        pricing: Some(get_pricing()),
     }
 }
 
-#[ic_cdk_macros::init]
-fn init() {
-    initialize();
+// Move providers from the unversioned encoding to the versioned one.
+fn migrate_providers() {
+    let legacy: Vec<(u64, LegacyProvider)> = LEGACY_PROVIDERS.with(|p| p.borrow().iter().collect());
+    if legacy.is_empty() {
+        return;
+    }
+    log!(INFO, "migrating {} providers", legacy.len());
+    for (provider_id, LegacyProvider(provider)) in legacy {
+        PROVIDERS.with(|p| p.borrow_mut().insert(provider_id, provider));
+        LEGACY_PROVIDERS.with(|p| p.borrow_mut().remove(&provider_id));
+    }
 }
 
-#[ic_cdk_macros::post_upgrade]
-fn post_upgrade() {
-    initialize();
-    // Remove these lines when ic_cdk::api::is_controller is supported.
-    authorize(ic_cdk::caller(), Auth::Admin);
-    authorize(ic_cdk::caller(), Auth::RegisterProvider);
-    authorize(ic_cdk::caller(), Auth::Rpc);
-    authorize(ic_cdk::caller(), Auth::FreeRpc);
-    stable_authorize(ic_cdk::caller());
+// Move permissions to distinct bits. The legacy RegisterProvider (3) was Admin | Rpc, so 3 is
+// ambiguous and is migrated to all of Admin, Rpc and RegisterProvider: an extra authorization
+// can be revoked with deauthorize, but a lost Admin could not be recovered.
+fn migrate_auth() {
+    let legacy: Vec<(PrincipalStorable, u32)> = LEGACY_AUTH.with(|a| a.borrow().iter().collect());
+    if legacy.is_empty() {
+        return;
+    }
+    log!(INFO, "migrating {} authorized principals", legacy.len());
+    for (principal, v) in legacy {
+        AUTH.with(|a| {
+            a.borrow_mut()
+                .insert(principal.clone(), migrate_auth_bits(v))
+        });
+        LEGACY_AUTH.with(|a| a.borrow_mut().remove(&principal));
+    }
 }
 
-fn initialize() {
-    SERVICE_HOSTS_ALLOWLIST
-        .with(|a| (*a.borrow_mut()) = AllowlistSet::from_iter(INITIAL_SERVICE_HOSTS_ALLOWLIST));
-
-    for principal in RPC_ALLOWLIST.iter() {
-        authorize(to_principal(principal), Auth::Rpc);
+fn migrate_auth_bits(v: u32) -> u32 {
+    let mut bits = 0;
+    if v & LEGACY_AUTH_ADMIN != 0 {
+        bits |= Auth::Admin as u32;
     }
-    for principal in REGISTER_PROVIDER_ALLOWLIST.iter() {
-        authorize(to_principal(principal), Auth::RegisterProvider);
+    if v & LEGACY_AUTH_RPC != 0 {
+        bits |= Auth::Rpc as u32;
     }
-    for principal in FREE_RPC_ALLOWLIST.iter() {
-        authorize(to_principal(principal), Auth::FreeRpc);
+    if v & (LEGACY_AUTH_ADMIN | LEGACY_AUTH_RPC) == LEGACY_AUTH_ADMIN | LEGACY_AUTH_RPC {
+        bits |= Auth::RegisterProvider as u32;
     }
-    for principal in AUTHORIZED_ADMIN.iter() {
-        authorize(to_principal(principal), Auth::Admin);
+    if v & LEGACY_AUTH_FREE_RPC != 0 {
+        bits |= Auth::FreeRpc as u32;
     }
+    bits
 }
 
-fn to_principal(principal: &str) -> Principal {
-    match Principal::from_text(principal) {
-        Ok(p) => p,
-        Err(e) => ic_cdk::trap(&format!(
-            "failed to convert Principal {} {:?}",
-            principal, e
-        )),
-    }
+fn initialize() {
+    // Seed the allowlist once; afterwards it is preserved across upgrades.
+    METADATA.with(|m| {
+        let mut metadata = m.borrow().get().clone();
+        if metadata.service_hosts_initialized != Some(true) {
+            SERVICE_HOSTS_ALLOWLIST.with(|a| {
+                for host in INITIAL_SERVICE_HOSTS_ALLOWLIST.iter() {
+                    a.borrow_mut().insert(StringStorable(host.to_string()), ());
+                }
+            });
+            metadata.service_hosts_initialized = Some(true);
+            m.borrow_mut().set(metadata).unwrap();
+        }
+    });
 }
 
 #[ic_cdk::query]
@@ -588,9 +3310,7 @@ fn http_request(request: CanisterHttpRequestArgument) -> HttpResponse {
 
 fn is_stable_authorized() -> Result<(), String> {
     AUTH_STABLE.with(|a| {
-        if
-        // ic_cdk::api::is_controller(&ic_cdk::caller()) ||  // Add when this is supported.
-        a.borrow().contains(&ic_cdk::caller()) {
+        if ic_cdk::api::is_controller(&ic_cdk::caller()) || a.borrow().contains(&ic_cdk::caller()) {
             Ok(())
         } else {
             Err("You are not stable authorized".to_string())
@@ -642,10 +3362,54 @@ fn authorize(principal: Principal, auth: Auth) {
     });
 }
 
+#[ic_cdk_macros::update(guard = "is_authorized")]
+#[candid_method]
+fn deauthorize(principal: Principal, auth: Auth) {
+    revoke(principal, auth);
+}
+
+fn revoke(principal: Principal, auth: Auth) {
+    AUTH.with(|a| {
+        let mut auth_map = a.borrow_mut();
+        let principal = PrincipalStorable(principal);
+        if let Some(v) = auth_map.get(&principal) {
+            let v = v & !(auth as u32);
+            if v == 0 {
+                auth_map.remove(&principal);
+            } else {
+                auth_map.insert(principal, v);
+            }
+        }
+    });
+}
+
+// Make principals exactly those with the authorization.
+fn set_authorized(principals: &[Principal], auth: Auth) {
+    for principal in get_authorized(auth.clone()) {
+        if !principals.contains(&principal) {
+            revoke(principal, auth.clone());
+        }
+    }
+    for principal in principals {
+        authorize(*principal, auth.clone());
+    }
+}
+
+#[ic_cdk_macros::query(guard = "is_authorized")]
+#[candid_method(query)]
+fn get_authorized(auth: Auth) -> Vec<Principal> {
+    let auth = auth as u32;
+    AUTH.with(|a| {
+        a.borrow()
+            .iter()
+            .filter(|(_, v)| v & auth != 0)
+            .map(|(principal, _)| principal.0)
+            .collect()
+    })
+}
+
 fn is_authorized() -> Result<(), String> {
-    if
-    // ic_cdk::api::is_controller(&ic_cdk::caller()) || // Add when this is supported.
-    authorized(Auth::Admin) {
+    if authorized(Auth::Admin) {
         Ok(())
     } else {
         Err("You are not authorized".to_string())
@@ -653,22 +3417,71 @@ fn is_authorized() -> Result<(), String> {
 }
 
 fn is_authorized_register_provider() -> Result<(), String> {
-    if
-    // ic_cdk::api::is_controller(&ic_cdk::caller()) || // Add when this is supported.
-    authorized(Auth::RegisterProvider) {
+    if authorized(Auth::RegisterProvider) || authorized(Auth::Admin) {
         Ok(())
     } else {
         Err("You are not authorized".to_string())
     }
 }
 
+fn rpc_access_allowed(caller: Principal) -> bool {
+    match get_rpc_access() {
+        RpcAccess::Open => true,
+        RpcAccess::Allowlist => principal_authorized(caller, Auth::Rpc),
+        RpcAccess::Denylist => {
+            !RPC_DENYLIST.with(|d| d.borrow().contains_key(&PrincipalStorable(caller)))
+        }
+    }
+}
+
+#[ic_cdk::query]
+#[candid_method(query)]
+fn get_rpc_access() -> RpcAccess {
+    CONFIG.with(|c| c.borrow().get().rpc_access)
+}
+
+#[ic_cdk::update(guard = "is_authorized")]
+#[candid_method]
+fn set_rpc_access(rpc_access: RpcAccess) {
+    log!(INFO, "set rpc access {:?}", rpc_access);
+    CONFIG.with(|c| {
+        let mut config = c.borrow().get().clone();
+        config.rpc_access = rpc_access;
+        c.borrow_mut().set(config).unwrap();
+    });
+}
+
+#[ic_cdk::query(guard = "is_authorized")]
+#[candid_method(query)]
+fn get_rpc_denylist() -> Vec<Principal> {
+    RPC_DENYLIST.with(|d| d.borrow().iter().map(|(k, _)| k.0).collect())
+}
+
+#[ic_cdk::update(guard = "is_authorized")]
+#[candid_method]
+fn add_rpc_denylist(principal: Principal) {
+    log!(INFO, "add rpc denylist {}", principal);
+    RPC_DENYLIST.with(|d| d.borrow_mut().insert(PrincipalStorable(principal), ()));
+}
+
+#[ic_cdk::update(guard = "is_authorized")]
+#[candid_method]
+fn remove_rpc_denylist(principal: Principal) {
+    log!(INFO, "remove rpc denylist {}", principal);
+    RPC_DENYLIST.with(|d| d.borrow_mut().remove(&PrincipalStorable(principal)));
+}
+
 fn authorized(auth: Auth) -> bool {
-    if auth == Auth::Rpc && OPEN_RPC_ACCESS {
+    if auth == Auth::Admin && ic_cdk::api::is_controller(&ic_cdk::caller()) {
         return true;
     }
-    let caller = PrincipalStorable(ic_cdk::caller());
+    principal_authorized(ic_cdk::caller(), auth)
+}
+
+fn principal_authorized(principal: Principal, auth: Auth) -> bool {
+    let principal = PrincipalStorable(principal);
     AUTH.with(|a| {
-        if let Some(v) = a.borrow().get(&caller) {
+        if let Some(v) = a.borrow().get(&principal) {
             (v & (auth as u32)) != 0
         } else {
             false
@@ -683,25 +3496,125 @@ fn encode_metrics(w: &mut ic_metrics_encoder::MetricsEncoder<Vec<u8>>) -> std::i
         ic_cdk::api::canister_version() as f64,
         "Canister version.",
     )?;
-    w.encode_gauge(
-        "stable_memory_pages",
-        ic_cdk::api::stable::stable64_size() as f64,
-        "Size of the stable memory allocated by this canister measured in 64K Wasm pages.",
+    w.encode_gauge(
+        "stable_memory_pages",
+        ic_cdk::api::stable::stable64_size() as f64,
+        "Size of the stable memory allocated by this canister measured in 64K Wasm pages.",
+    )?;
+    w.encode_counter(
+        "json_rpc_requests",
+        get_metric!(json_rpc_requests) as f64,
+        "Number of json_rpc_request() calls.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_cycles_charged",
+        get_metric!(json_rpc_request_cycles_charged) as f64,
+        "Cycles charged by json_rpc_request() calls.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_cycles_refunded",
+        get_metric!(json_rpc_request_cycles_refunded) as f64,
+        "Cycles charged by json_rpc_request() calls and refunded.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_err_no_permission",
+        get_metric!(json_rpc_request_err_no_permission) as f64,
+        "Number of json_rpc_request() calls without permission.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_err_service_url_host_not_allowed",
+        get_metric!(json_rpc_request_err_service_url_host_not_allowed) as f64,
+        "Number of json_rpc_request() calls to a host not in the allowlist.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_err_http_request_error",
+        get_metric!(json_rpc_request_err_http_request_error) as f64,
+        "Number of json_rpc_request() calls with a failed HTTPS outcall.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_err_http_request_error_cycles_refunded",
+        get_metric!(json_rpc_request_err_http_request_error_cycles_refunded) as f64,
+        "Cycles of failed HTTPS outcalls refunded to the caller.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_response_bytes_reserved",
+        get_metric!(json_rpc_request_response_bytes_reserved) as f64,
+        "Response bytes reserved (max_response_bytes) by json_rpc_request() calls.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_response_bytes_received",
+        get_metric!(json_rpc_request_response_bytes_received) as f64,
+        "Response bytes received by json_rpc_request() calls.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_response_bytes_cycles_refunded",
+        get_metric!(json_rpc_request_response_bytes_cycles_refunded) as f64,
+        "Cycles of response bytes not received refunded to the caller.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_auto_max_response_bytes_retries",
+        get_metric!(json_rpc_request_auto_max_response_bytes_retries) as f64,
+        "Number of json_rpc_request() calls with max_response_bytes = 0 retried with a larger limit.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_err_http_status",
+        get_metric!(json_rpc_request_err_http_status) as f64,
+        "Number of json_rpc_request() calls with a non-2xx HTTP status.",
+    )?;
+    w.encode_counter(
+        "json_rpc_request_err_json_rpc_error",
+        get_metric!(json_rpc_request_err_json_rpc_error) as f64,
+        "Number of json_rpc_request() calls with a JSON RPC error response.",
+    )?;
+    w.encode_counter(
+        "account_cycles_deposited",
+        get_metric!(account_cycles_deposited) as f64,
+        "Cycles deposited by deposit_cycles() calls.",
+    )?;
+    w.encode_counter(
+        "account_cycles_withdrawn",
+        get_metric!(account_cycles_withdrawn) as f64,
+        "Cycles withdrawn by withdraw_balance() calls.",
+    )?;
+    w.encode_counter(
+        "account_cycles_debited",
+        get_metric!(account_cycles_debited) as f64,
+        "Cycles paid for requests from prepaid balances.",
+    )?;
+    w.encode_counter(
+        "account_cycles_refunded",
+        get_metric!(account_cycles_refunded) as f64,
+        "Cycles charged for requests returned to prepaid balances.",
     )?;
     w.encode_counter(
-        "json_rpc_requests",
-        get_metric!(json_rpc_requests) as f64,
-        "Number of json_rpc_request() calls.",
+        "json_rpc_multi_requests",
+        get_metric!(json_rpc_multi_requests) as f64,
+        "Number of json_rpc_multi_request() calls.",
     )?;
     w.encode_counter(
-        "json_rpc_request_cycles_charged",
-        get_metric!(json_rpc_request_cycles_charged) as f64,
-        "Cycles charged by json_rpc_request() calls.",
+        "json_rpc_multi_request_err_inconsistent",
+        get_metric!(json_rpc_multi_request_err_inconsistent) as f64,
+        "Number of json_rpc_multi_request() calls without consensus.",
     )?;
     w.encode_counter(
-        "json_rpc_request_cycles_refunded",
-        get_metric!(json_rpc_request_cycles_refunded) as f64,
-        "Cycles refunded by json_rpc_request() calls.",
+        "json_rpc_chain_requests",
+        get_metric!(json_rpc_chain_requests) as f64,
+        "Number of json_rpc_chain_request() calls.",
+    )?;
+    w.encode_counter(
+        "json_rpc_chain_request_failovers",
+        get_metric!(json_rpc_chain_request_failovers) as f64,
+        "Number of json_rpc_chain_request() retries with another provider.",
+    )?;
+    w.encode_counter(
+        "eth_get_logs_requests",
+        get_metric!(eth_get_logs_requests) as f64,
+        "Number of eth_get_logs() calls.",
+    )?;
+    w.encode_counter(
+        "eth_get_logs_range_splits",
+        get_metric!(eth_get_logs_range_splits) as f64,
+        "Number of eth_get_logs() block ranges split after too many results.",
     )?;
     METRICS.with(|m| {
         m.borrow()
@@ -748,20 +3661,938 @@ fn check_candid_interface() {
 
 #[test]
 fn check_json_rpc_cycles_cost() {
-    let base_cost = json_rpc_cycles_cost(
+    let pricing = Pricing::default();
+    let base_cost = json_rpc_cost_breakdown(
+        &pricing,
         "{\"jsonrpc\":\"2.0\",\"method\":\"eth_gasPrice\",\"params\":[],\"id\":1}",
         "https://cloudflare-eth.com",
+        0,
         1000,
-    );
+        0,
+    )
+    .total;
     let s10 = "0123456789";
-    let base_cost_s10 = json_rpc_cycles_cost(
+    let base_cost_s10 = json_rpc_cost_breakdown(
+        &pricing,
         &("{\"jsonrpc\":\"2.0\",\"method\":\"eth_gasPrice\",\"params\":[],\"id\":1}".to_string()
             + s10),
         "https://cloudflare-eth.com",
+        0,
         1000,
-    );
+        0,
+    )
+    .total;
     assert_eq!(
-        base_cost + 10 * (INGRESS_MESSAGE_BYTE_RECEIVED_COST + HTTP_OUTCALL_BYTE_RECEIEVED_COST),
+        base_cost
+            + 10 * (INGRESS_MESSAGE_BYTE_RECEIVED_COST
+                + HTTP_OUTCALL_REQUEST_BYTE_COST * SUBNET_SIZE) as u128,
         base_cost_s10
+    );
+    let breakdown = json_rpc_cost_breakdown(
+        &pricing,
+        "{\"jsonrpc\":\"2.0\",\"method\":\"eth_gasPrice\",\"params\":[],\"id\":1}",
+        "https://cloudflare-eth.com",
+        0,
+        1000,
+        7,
+    );
+    // (3_000_000 + 60_000 * 13) * 13
+    assert_eq!(breakdown.outcall_base, 49_140_000);
+    assert_eq!(breakdown.provider_fee, 7);
+    assert_eq!(
+        breakdown.ingress + breakdown.outcall_base + breakdown.outcall_bytes,
+        base_cost
+    );
+    assert_eq!(breakdown.total, base_cost + 7);
+
+    // Costs scale with the number of nodes in the subnet.
+    let system_subnet = Pricing {
+        subnet_size: 34,
+        ..Pricing::default()
+    };
+    let breakdown_34 = json_rpc_cost_breakdown(
+        &system_subnet,
+        "{\"jsonrpc\":\"2.0\",\"method\":\"eth_gasPrice\",\"params\":[],\"id\":1}",
+        "https://cloudflare-eth.com",
+        0,
+        1000,
+        0,
+    );
+    assert_eq!(breakdown_34.outcall_base, (3_000_000 + 60_000 * 34) * 34);
+    assert_eq!(
+        breakdown_34.outcall_bytes,
+        breakdown.outcall_bytes * 34 / 13
+    );
+    assert_eq!(breakdown_34.ingress, breakdown.ingress * 34 / 13);
+
+    // Provider request headers are charged as outcall request bytes only.
+    let breakdown_headers = json_rpc_cost_breakdown(
+        &pricing,
+        "{\"jsonrpc\":\"2.0\",\"method\":\"eth_gasPrice\",\"params\":[],\"id\":1}",
+        "https://cloudflare-eth.com",
+        10,
+        1000,
+        0,
+    );
+    assert_eq!(breakdown_headers.ingress, breakdown.ingress);
+    assert_eq!(
+        breakdown_headers.outcall_bytes,
+        breakdown.outcall_bytes + 10 * (HTTP_OUTCALL_REQUEST_BYTE_COST * SUBNET_SIZE) as u128
+    );
+
+    // The refund for unused response bytes never exceeds their cost.
+    assert_eq!(
+        unused_response_bytes_cost(&pricing, 1000, 200),
+        (HTTP_OUTCALL_RESPONSE_BYTE_COST * SUBNET_SIZE * 800) as u128
+    );
+    assert_eq!(unused_response_bytes_cost(&pricing, 1000, 1000), 0);
+    assert_eq!(unused_response_bytes_cost(&pricing, 1000, 2000), 0);
+    assert!(unused_response_bytes_cost(&pricing, 1000, 0) <= breakdown.outcall_bytes);
+
+    // The refund is bounded by the raw response, which is larger than the transformed body.
+    let headers = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nDate: Mon, 01 May 2023 00:00:00 GMT\r\nContent-Length: 250\r\n\r\n";
+    let raw = r#"{
+  "jsonrpc": "2.0",
+  "id": 1,
+  "result": {
+    "number": "0x10",
+    "hash": "0x88e96d4537bea4d9c05d12549907b32561d3bf31f45aae734cdc119f13406cb6",
+    "totalDifficulty": "0x7fe000000000000000000000000000000000000000000000000000"
+  }
+}"#;
+    let options = transform_options(
+        r#"{"jsonrpc":"2.0","method":"eth_getBlockByNumber","params":["0x10",false],"id":1}"#,
+        None,
+    )
+    .unwrap();
+    let body = transform_body(&options, raw.as_bytes().to_vec());
+    assert!(body.len() < raw.len());
+    let raw_bytes = (headers.len() + raw.len()) as u64;
+    assert!(raw_response_bytes_bound(body.len() as u64) >= raw_bytes);
+    assert!(
+        unused_response_bytes_cost(&pricing, 2_000, raw_response_bytes_bound(body.len() as u64))
+            <= unused_response_bytes_cost(&pricing, 2_000, raw_bytes)
+    );
+}
+
+#[test]
+fn check_json_rpc_provider_cycles_cost() {
+    assert_eq!(json_rpc_provider_cycles_cost(100, 50, 10, 0), 10);
+    // Each request and response byte is charged.
+    assert_eq!(json_rpc_provider_cycles_cost(100, 50, 10, 2), 10 + 2 * 150);
+    assert_eq!(
+        json_rpc_provider_cycles_cost(100, 1_000, 10, 2)
+            - json_rpc_provider_cycles_cost(100, 0, 10, 2),
+        2_000
+    );
+    // Large responses do not overflow.
+    assert_eq!(
+        json_rpc_provider_cycles_cost(0, 2_000_000, 0, u32::MAX as u64),
+        2_000_000 * u32::MAX as u128
+    );
+}
+
+#[test]
+fn check_service_host_matching() {
+    let url = |u: &str| url::Url::parse(u).unwrap();
+
+    let exact = ServiceHost::parse("cloudflare-eth.com").unwrap();
+    assert_eq!(exact.to_string(), "cloudflare-eth.com");
+    assert!(exact.matches(&url("https://cloudflare-eth.com/v1/mainnet")));
+    assert!(exact.matches(&url("https://cloudflare-eth.com:443")));
+    assert!(!exact.matches(&url("http://cloudflare-eth.com")));
+    assert!(!exact.matches(&url("https://cloudflare-eth.com:8443")));
+    assert!(!exact.matches(&url("https://evil-cloudflare-eth.com")));
+
+    let path = ServiceHost::parse("rpc.ankr.com/eth_goerli/").unwrap();
+    assert_eq!(path.to_string(), "rpc.ankr.com/eth_goerli");
+    assert!(path.matches(&url("https://rpc.ankr.com/eth_goerli")));
+    assert!(path.matches(&url("https://rpc.ankr.com/eth_goerli/my-api-key")));
+    assert!(!path.matches(&url("https://rpc.ankr.com/eth_goerlix")));
+    assert!(!path.matches(&url("https://rpc.ankr.com/eth")));
+
+    let wildcard = ServiceHost::parse("*.infura.io").unwrap();
+    assert!(wildcard.matches(&url("https://mainnet.infura.io/v3/key")));
+    assert!(!wildcard.matches(&url("https://infura.io")));
+    assert!(!wildcard.matches(&url("https://notinfura.io")));
+
+    let port = ServiceHost::parse("http://localhost:8545").unwrap();
+    assert_eq!(port.to_string(), "http://localhost:8545");
+    assert!(port.matches(&url("http://localhost:8545/")));
+    assert!(!port.matches(&url("http://localhost:8546/")));
+
+    assert!(ServiceHost::parse("ftp://example.com").is_err());
+    assert!(ServiceHost::parse("example.com:port").is_err());
+    assert!(ServiceHost::parse("*.").is_err());
+}
+
+#[test]
+fn check_consensus() {
+    assert!(ConsensusStrategy::Threshold { min: 1, total: 1 }
+        .validate()
+        .is_ok());
+    for (min, total) in [(0, 2), (3, 2)] {
+        assert!(matches!(
+            ConsensusStrategy::Threshold { min, total }.validate(),
+            Err(EthRpcError::InvalidConsensusStrategy(_))
+        ));
+    }
+    let ok = |provider_id: u64, body: &str| ProviderResult {
+        provider_id,
+        result: Ok(body.as_bytes().to_vec()),
+    };
+    let err = |provider_id: u64| ProviderResult {
+        provider_id,
+        result: Err(EthRpcError::HttpRequestError {
+            code: 2,
+            message: "timeout".to_string(),
+        }),
+    };
+    let a = r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#;
+    let a_reordered = r#"{ "result": "0x1", "id": 1, "jsonrpc": "2.0" }"#;
+    let b = r#"{"jsonrpc":"2.0","id":1,"result":"0x2"}"#;
+    let canonical_a = br#"{"id":1,"jsonrpc":"2.0","result":"0x1"}"#.to_vec();
+    assert_eq!(
+        canonical_json(br#"{"b": [{"d": 1, "c": 2}], "a": {"f": 3, "e": 4}}"#),
+        br#"{"a":{"e":4,"f":3},"b":[{"c":2,"d":1}]}"#.to_vec()
+    );
+
+    assert_eq!(
+        consensus(
+            &ConsensusStrategy::Equality,
+            vec![ok(0, a), ok(1, a_reordered)]
+        )
+        .unwrap(),
+        canonical_a
+    );
+    assert!(matches!(
+        consensus(&ConsensusStrategy::Equality, vec![ok(0, a), ok(1, b)]),
+        Err(EthRpcError::Inconsistent(results)) if results.len() == 2
+    ));
+    assert!(consensus(&ConsensusStrategy::Equality, vec![ok(0, a), err(1)]).is_err());
+
+    let threshold = ConsensusStrategy::Threshold { min: 2, total: 3 };
+    assert_eq!(
+        consensus(&threshold, vec![ok(0, b), ok(1, a), ok(2, a_reordered)]).unwrap(),
+        canonical_a
+    );
+    assert!(consensus(&threshold, vec![ok(0, a), ok(1, b), err(2)]).is_err());
+
+    assert_eq!(
+        consensus(&ConsensusStrategy::Any, vec![err(0), ok(1, a)]).unwrap(),
+        canonical_a
+    );
+    assert!(consensus(&ConsensusStrategy::Any, vec![err(0), err(1)]).is_err());
+}
+
+#[test]
+fn check_should_failover() {
+    let response = |status: u16, body: &str| {
+        http_response_result(&Nat::from(status), body.as_bytes().to_vec())
+    };
+    assert!(!should_failover(&response(
+        200,
+        r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#
+    )));
+    assert!(should_failover(&response(
+        200,
+        r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"header not found"}}"#
+    )));
+    assert!(should_failover(&response(
+        200,
+        r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32603,"message":"internal error"}}"#
+    )));
+    assert!(!should_failover(&response(
+        200,
+        r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32602,"message":"invalid params"}}"#
+    )));
+    assert!(should_failover(&response(429, "Too Many Requests")));
+    assert!(should_failover(&response(503, "")));
+    assert!(!should_failover(&response(401, "Unauthorized")));
+    assert!(should_failover(&Err(EthRpcError::HttpRequestError {
+        code: 2,
+        message: "timeout".to_string(),
+    })));
+    assert!(!should_failover(&Err(EthRpcError::TooFewCycles(
+        "requires 1 cycles, got 0 cycles".to_string()
+    ))));
+}
+
+#[test]
+fn check_decode_transaction_receipt() {
+    let body = br#"{"jsonrpc":"2.0","id":1,"result":{
+        "blockHash":"0xa957d47df264a31badc3ae823e10ac1d444b098d9b73d204c40426e57f47e8c3",
+        "blockNumber":"0xeff35f",
+        "contractAddress":null,
+        "cumulativeGasUsed":"0xa12515",
+        "effectiveGasPrice":"0x5a9c688d4",
+        "from":"0x6221a9c005f6e47eb398fd867784cacfdcfff4e7",
+        "gasUsed":"0xb4c8",
+        "logs":[{
+            "address":"0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+            "topics":["0x8c5be1e5ebec7d5bd14f71427d1e84f3dd0314c0f7b2291e5b200ac8c7c3b925"],
+            "data":"0x0000000000000000000000000000000000000000000000000000000000000001",
+            "blockNumber":"0xeff35f",
+            "transactionHash":"0x85d995eba9763907fdf35cd2034144dd9d53ce32cbec21349d4b12823c6860c5",
+            "transactionIndex":"0x29",
+            "blockHash":"0xa957d47df264a31badc3ae823e10ac1d444b098d9b73d204c40426e57f47e8c3",
+            "logIndex":"0x0",
+            "removed":false}],
+        "status":"0x1",
+        "to":"0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+        "transactionHash":"0x85d995eba9763907fdf35cd2034144dd9d53ce32cbec21349d4b12823c6860c5",
+        "transactionIndex":"0x29",
+        "type":"0x2"}}"#;
+    let receipt = decode_transaction_receipt(&json_rpc_result(body).unwrap()).unwrap();
+    assert_eq!(receipt.block_number, 0xeff35f);
+    assert_eq!(receipt.gas_used, Nat::from(0xb4c8u64));
+    assert_eq!(receipt.effective_gas_price, Some(Nat::from(0x5a9c688d4u64)));
+    assert_eq!(receipt.contract_address, None);
+    assert_eq!(receipt.status, Some(1));
+    assert_eq!(receipt.logs.len(), 1);
+    assert_eq!(receipt.logs[0].log_index, Some(0));
+    assert_eq!(receipt.logs[0].topics.len(), 1);
+
+    assert!(matches!(
+        json_rpc_result(br#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"x"}}"#),
+        Err(EthRpcError::JsonRpcError { code: -32000, .. })
+    ));
+    assert!(decode_u64(&serde_json::json!("12")).is_err());
+    assert_eq!(
+        decode_nat(&serde_json::json!("0x1000000000000000000000")).unwrap(),
+        Nat(num::BigUint::from(1u128 << 84))
+    );
+}
+
+#[test]
+fn check_eth_get_logs_ranges() {
+    let ranges = |from, to| log_block_ranges(from, to, 2_000, 64);
+    assert!(matches!(
+        ranges(10, 9),
+        Err(EthRpcError::InvalidBlockRange(_))
+    ));
+    assert_eq!(ranges(10, 10).unwrap(), vec![(10, 10)]);
+    assert_eq!(
+        ranges(0, 4_500).unwrap(),
+        vec![(0, 1_999), (2_000, 3_999), (4_000, 4_500)]
+    );
+    assert_eq!(
+        ranges(u64::MAX - 1, u64::MAX).unwrap(),
+        vec![(u64::MAX - 1, u64::MAX)]
+    );
+    assert_eq!(ranges(0, 64 * 2_000 - 1).unwrap().len(), 64);
+    assert!(matches!(
+        ranges(0, 64 * 2_000),
+        Err(EthRpcError::InvalidBlockRange(_))
+    ));
+    // Rejected without iterating over the range.
+    assert!(matches!(
+        ranges(0, u64::MAX),
+        Err(EthRpcError::InvalidBlockRange(_))
+    ));
+    assert!(matches!(
+        log_block_ranges(0, u64::MAX, 1, 64),
+        Err(EthRpcError::InvalidBlockRange(_))
+    ));
+
+    let filter = LogFilter {
+        addresses: vec!["0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2".to_string()],
+        topics: vec![
+            vec!["0xa".to_string()],
+            vec![],
+            vec!["0xb".to_string(), "0xc".to_string()],
+        ],
+        from_block: BlockTag::Latest,
+        to_block: BlockTag::Latest,
+    };
+    assert_eq!(
+        log_filter_params(&filter, 16, 255),
+        serde_json::json!([{
+            "address": ["0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"],
+            "topics": ["0xa", null, ["0xb", "0xc"]],
+            "fromBlock": "0x10",
+            "toBlock": "0xff",
+        }])
+    );
+
+    assert!(should_split_log_range(&EthRpcError::HttpRequestError {
+        code: 1,
+        message: "Http body exceeds size limit of 2000000 bytes.".to_string(),
+    }));
+    assert!(should_split_log_range(&EthRpcError::JsonRpcError {
+        code: -32005,
+        message: "query returned more than 10000 results".to_string(),
+        data: None,
+    }));
+    assert!(should_split_log_range(&EthRpcError::JsonRpcError {
+        code: -32602,
+        message: "Log response size exceeded.".to_string(),
+        data: None,
+    }));
+    assert!(!should_split_log_range(&EthRpcError::ProviderNotFound));
+}
+
+#[test]
+fn check_http_response_result() {
+    assert_eq!(
+        http_response_result(&Nat::from(200u16), b"not json".to_vec()).unwrap(),
+        b"not json".to_vec()
+    );
+    match http_response_result(&Nat::from(429u16), b"rate limited".to_vec()) {
+        Err(EthRpcError::HttpStatus { code, body }) => {
+            assert_eq!(code, 429);
+            assert_eq!(body, "rate limited");
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+    let body = br#"{"jsonrpc":"2.0","id":1,"error":{"code":3,"message":"execution reverted","data":"0x08c379a0"}}"#;
+    match http_response_result(&Nat::from(200u16), body.to_vec()) {
+        Err(EthRpcError::JsonRpcError {
+            code,
+            message,
+            data,
+        }) => {
+            assert_eq!(code, 3);
+            assert_eq!(message, "execution reverted");
+            assert_eq!(data.as_deref(), Some(r#""0x08c379a0""#));
+        }
+        r => panic!("unexpected result {:?}", r),
+    }
+    let body = br#"{"jsonrpc":"2.0","id":1,"result":"0x1","error":null}"#;
+    assert_eq!(
+        http_response_result(&Nat::from(200u16), body.to_vec()).unwrap(),
+        body.to_vec()
+    );
+    assert_eq!(
+        json_rpc_result(body).unwrap(),
+        serde_json::Value::String("0x1".to_string())
+    );
+}
+
+#[test]
+fn check_transform() {
+    let response = |body: &str, context: Vec<u8>| {
+        transform(TransformArgs {
+            response: HttpResponse {
+                status: Nat::from(200u16),
+                headers: vec![HttpHeader {
+                    name: "Date".to_string(),
+                    value: "Mon, 01 May 2023 00:00:00 GMT".to_string(),
+                }],
+                body: body.as_bytes().to_vec(),
+            },
+            context,
+        })
+    };
+    let body = r#"{ "result": {"status": "0x1", "logs": [{"logIndex": "0x0", "blockTimestamp": "0x64"}]}, "id": 1, "jsonrpc": "2.0" }"#;
+
+    let result = response(body, vec![]);
+    assert!(result.headers.is_empty());
+    assert_eq!(result.body, body.as_bytes());
+
+    let options = transform_options(
+        r#"{"jsonrpc":"2.0","method":"eth_getTransactionReceipt","params":["0x1"],"id":1}"#,
+        None,
+    )
+    .unwrap();
+    assert_eq!(options.drop_fields, vec!["result.logs.blockTimestamp"]);
+    assert_eq!(
+        String::from_utf8(response(body, Encode!(&options).unwrap()).body).unwrap(),
+        r#"{"id":1,"jsonrpc":"2.0","result":{"logs":[{"logIndex":"0x0"}],"status":"0x1"}}"#
+    );
+
+    let chain_id = r#"{"jsonrpc":"2.0","method":"eth_chainId","id":1}"#;
+    let options = transform_options(chain_id, None).unwrap();
+    assert_eq!(
+        options,
+        TransformOptions {
+            canonicalize: true,
+            drop_fields: vec![],
+            set_fields: vec![],
+        }
+    );
+    assert_eq!(
+        response("not json", Encode!(&options).unwrap()).body,
+        b"not json"
+    );
+
+    // Numbers are not rounded, see the arbitrary_precision feature of serde_json.
+    let numbers =
+        r#"{"id":1,"result":[340282366920938463463374607431768211455,-18446744073709551617,1.10]}"#;
+    assert_eq!(
+        String::from_utf8(response(numbers, Encode!(&options).unwrap()).body).unwrap(),
+        numbers
+    );
+    assert_eq!(canonical_json(numbers.as_bytes()), numbers.as_bytes());
+
+    // The caller may pick a preset by name or give the options.
+    let options = transform_options(
+        chain_id,
+        Some(Transform::Preset("eth_getTransactionReceipt".to_string())),
+    )
+    .unwrap();
+    assert_eq!(options.drop_fields, vec!["result.logs.blockTimestamp"]);
+    assert!(matches!(
+        transform_options(chain_id, Some(Transform::Preset("eth_foo".to_string()))),
+        Err(EthRpcError::InvalidTransform(_))
+    ));
+    let options = TransformOptions {
+        canonicalize: true,
+        drop_fields: vec!["result.logs".to_string()],
+        set_fields: vec![
+            SetField {
+                path: "id".to_string(),
+                value: "0".to_string(),
+            },
+            SetField {
+                path: "result.missing".to_string(),
+                value: "0".to_string(),
+            },
+        ],
+    };
+    let options = transform_options(chain_id, Some(Transform::Options(options))).unwrap();
+    assert_eq!(
+        String::from_utf8(response(body, Encode!(&options).unwrap()).body).unwrap(),
+        r#"{"id":0,"jsonrpc":"2.0","result":{"status":"0x1"}}"#
+    );
+    let batch =
+        r#"[{"jsonrpc":"2.0","id":7,"result":"0x1"},{"jsonrpc":"2.0","id":8,"result":"0x2"}]"#;
+    assert_eq!(
+        String::from_utf8(response(batch, Encode!(&options).unwrap()).body).unwrap(),
+        r#"[{"id":0,"jsonrpc":"2.0","result":"0x1"},{"id":0,"jsonrpc":"2.0","result":"0x2"}]"#
+    );
+    let invalid = TransformOptions {
+        set_fields: vec![SetField {
+            path: "id".to_string(),
+            value: "not json".to_string(),
+        }],
+        ..TransformOptions::default()
+    };
+    assert!(matches!(
+        transform_options(chain_id, Some(Transform::Options(invalid))),
+        Err(EthRpcError::InvalidTransform(_))
+    ));
+}
+
+#[test]
+fn check_usage_key() {
+    let caller = Principal::management_canister();
+    let other = Principal::anonymous();
+    for key in [
+        UsageKey { caller, index: 0 },
+        UsageKey {
+            caller: other,
+            index: u64::MAX,
+        },
+    ] {
+        let bytes = key.to_bytes();
+        assert_eq!(bytes.len() as u32, UsageKey::MAX_SIZE);
+        assert!(UsageKey::from_bytes(bytes) == key);
+    }
+    assert!(UsageKey { caller, index: 1 } < UsageKey { caller, index: 2 });
+}
+
+#[test]
+fn check_debit_balance() {
+    let caller = Principal::management_canister();
+    let usage = |cycles: u128| Usage {
+        timestamp: 0,
+        host: "cloudflare-eth.com".to_string(),
+        provider_id: Some(0),
+        cycles,
+    };
+    credit_balance(caller, 1_000);
+    assert_eq!(debit_balance(caller, usage(300)).unwrap(), 0);
+    assert_eq!(debit_balance(caller, usage(200)).unwrap(), 1);
+    assert!(matches!(
+        debit_balance(caller, usage(600)),
+        Err(EthRpcError::TooFewCycles(_))
+    ));
+    assert_eq!(get_account(caller).balance, 500);
+    let history: Vec<u128> = usage_history(caller).iter().map(|u| u.cycles).collect();
+    assert_eq!(history, vec![300, 200]);
+    assert!(usage_history(Principal::anonymous()).is_empty());
+}
+
+#[test]
+fn check_record_response_size() {
+    let payload = |method: &str| {
+        format!(
+            "{{\"jsonrpc\":\"2.0\",\"method\":\"{}\",\"params\":[],\"id\":1}}",
+            method
+        )
+    };
+    record_response_size(&payload(&"x".repeat(65)), None, "cloudflare-eth.com", 100);
+    assert!(RESPONSE_SIZES.with(|r| r.borrow().is_empty()));
+    for i in 0..AUTO_RESPONSE_SIZE_MAX_KEYS + 10 {
+        record_response_size(
+            &payload(&format!("m{}", i)),
+            None,
+            "cloudflare-eth.com",
+            100,
+        );
+    }
+    assert_eq!(
+        RESPONSE_SIZES.with(|r| r.borrow().len()),
+        AUTO_RESPONSE_SIZE_MAX_KEYS
+    );
+    // Existing keys are still recorded.
+    record_response_size(&payload("m0"), None, "cloudflare-eth.com", 200);
+    assert_eq!(
+        auto_max_response_bytes(&payload("m0"), None, "https://cloudflare-eth.com"),
+        250
+    );
+}
+
+#[test]
+fn check_response_size_limit() {
+    assert_eq!(
+        response_size_limit(&VecDeque::new()),
+        AUTO_MAX_RESPONSE_BYTES_DEFAULT
+    );
+    assert_eq!(response_size_limit(&VecDeque::from(vec![400])), 400 + 100);
+    // The 95th percentile of 1..=100 is 95.
+    let sizes: VecDeque<u64> = (1..=100).rev().map(|i| i * 100).collect();
+    assert_eq!(response_size_limit(&sizes), 9_500 + 2_375);
+    assert_eq!(
+        response_size_limit(&VecDeque::from(vec![MAX_RESPONSE_BYTES])),
+        MAX_RESPONSE_BYTES
+    );
+    assert!(is_response_too_large(
+        "Http body exceeds size limit of 2000 bytes."
+    ));
+    assert!(!is_response_too_large("Connection refused"));
+}
+
+#[test]
+fn check_parse_config_bundle() {
+    let bundle = parse_config_bundle(
+        r#"{
+  // Comments are allowed.
+  rpc_access: "Denylist",
+  service_hosts: ["*.infura.io", "rpc.ankr.com/eth_goerli"],
+  pricing: {
+    subnet_size: 34,
+    ingress_message_received_cost: 1200000,
+    ingress_message_byte_received_cost: 2000,
+    http_outcall_request_base_cost: 3000000,
+    http_outcall_request_per_node_cost: 60000,
+    http_outcall_request_byte_cost: 400,
+    http_outcall_response_byte_cost: 800,
+  },
+}"#,
+    )
+    .unwrap();
+    assert_eq!(bundle.rpc_access, Some(RpcAccess::Denylist));
+    assert_eq!(
+        bundle.service_hosts.unwrap()[1].0.to_string(),
+        "rpc.ankr.com/eth_goerli"
+    );
+    assert_eq!(bundle.pricing.unwrap().subnet_size, 34);
+
+    let errors = parse_config_bundle("{\n  rpc_alowlist: [],\n}").unwrap_err();
+    assert!(errors[0].starts_with("line 2,"), "{:?}", errors);
+    let errors = parse_config_bundle(
+        "{\n  rpc_access: \"Open\",\n  service_hosts: [\"ftp://example.com\"],\n}",
+    )
+    .unwrap_err();
+    assert_eq!(errors, vec!["line 3, column 18: unsupported scheme ftp"]);
+
+    PROVIDERS.with(|p| {
+        p.borrow_mut()
+            .insert(0, test_provider("https://cloudflare-eth.com", ""))
+    });
+    let errors = parse_config_bundle(
+        r#"{
+  /* The provider_id: of each provider is the location of its errors. */
+  pricing: {
+    subnet_size: 0,
+    ingress_message_received_cost: 1200000,
+    ingress_message_byte_received_cost: 2000,
+    http_outcall_request_base_cost: 3000000,
+    http_outcall_request_per_node_cost: 60000,
+    http_outcall_request_byte_cost: 400,
+    http_outcall_response_byte_cost: 800,
+  },
+  providers: [
+    {
+      provider_id: 0, owner: "2vxsx-fae", chain_id: 1,
+      service_url: "https://cloudflare-eth.com", // provider_id: 3
+      cycles_per_call: 0, cycles_per_message_byte: 0, active: true,
+    },
+    {
+      "provider_id": 7, owner: "2vxsx-fae", chain_id: 1,
+      service_url: "https://cloudflare-eth.com",
+      cycles_per_call: 0, cycles_per_message_byte: 0, active: true,
+    },
+  ],
+}"#,
     )
+    .unwrap_err();
+    assert_eq!(
+        errors,
+        vec![
+            "line 3, column 3: pricing: subnet_size must be positive",
+            // The service hosts allowlist is empty.
+            "line 14, column 7: providers[0]: ServiceUrlHostNotAllowed",
+            "line 19, column 7: providers[1]: provider 7 not found",
+        ]
+    );
+
+    // Providers are checked against the service hosts of the document.
+    let provider = r#"
+  providers: [
+    {
+      provider_id: 0, owner: "2vxsx-fae", chain_id: 1,
+      service_url: "https://cloudflare-eth.com",
+      cycles_per_call: 0, cycles_per_message_byte: 0, active: true,
+    },
+  ],"#;
+    assert!(parse_config_bundle(&format!(
+        "{{\n  service_hosts: [\"cloudflare-eth.com\"],{}\n}}",
+        provider
+    ))
+    .is_ok());
+    let errors = parse_config_bundle(&format!(
+        "{{\n  service_hosts: [\"*.infura.io\"],{}\n}}",
+        provider
+    ))
+    .unwrap_err();
+    assert_eq!(
+        errors,
+        vec!["line 5, column 7: providers[0]: ServiceUrlHostNotAllowed"]
+    );
+    let errors = parse_config_bundle("{\n  service_hosts: [\"*.infura.io\"],\n}").unwrap_err();
+    assert_eq!(
+        errors,
+        vec!["line 2, column 3: service_hosts: provider 0: ServiceUrlHostNotAllowed"]
+    );
+}
+
+#[test]
+fn check_migrate_auth_bits() {
+    assert_eq!(migrate_auth_bits(1), Auth::Admin as u32);
+    assert_eq!(migrate_auth_bits(2), Auth::Rpc as u32);
+    assert_eq!(
+        migrate_auth_bits(3),
+        Auth::Admin as u32 | Auth::RegisterProvider as u32 | Auth::Rpc as u32
+    );
+    assert_eq!(
+        migrate_auth_bits(5),
+        Auth::Admin as u32 | Auth::FreeRpc as u32
+    );
+    // Granted to every upgrader by earlier versions.
+    assert_eq!(
+        migrate_auth_bits(7),
+        Auth::Admin as u32
+            | Auth::RegisterProvider as u32
+            | Auth::Rpc as u32
+            | Auth::FreeRpc as u32
+    );
+}
+
+#[cfg(test)]
+fn test_provider(service_url: &str, api_key: &str) -> Provider {
+    Provider {
+        provider_id: 0,
+        owner: Principal::anonymous(),
+        chain_id: 1,
+        service_url: service_url.to_string(),
+        api_key: api_key.to_string(),
+        cycles_per_call: 0,
+        cycles_per_message_byte: 0,
+        cycles_owed: 0,
+        active: None,
+        maintenance: None,
+        headers: None,
+    }
+}
+
+#[test]
+fn check_provider_storage() {
+    let provider = Provider {
+        provider_id: 3,
+        cycles_per_call: 10,
+        cycles_per_message_byte: 1,
+        active: Some(true),
+        ..test_provider("https://cloudflare-eth.com", "")
+    };
+    let bytes = provider.to_bytes();
+    assert_eq!(bytes[0], PROVIDER_STORAGE_VERSION);
+    let decoded = Provider::from_bytes(bytes);
+    assert_eq!(decoded.provider_id, 3);
+    assert_eq!(decoded.service_url, provider.service_url);
+
+    let legacy = LegacyProvider(provider);
+    let LegacyProvider(decoded) = LegacyProvider::from_bytes(legacy.to_bytes());
+    assert_eq!(decoded.cycles_per_call, 10);
+}
+
+#[test]
+fn check_provider_headers() {
+    let mut provider = test_provider("https://eth-mainnet.g.alchemy.com/v2", "/secret");
+    assert_eq!(
+        provider.url(),
+        "https://eth-mainnet.g.alchemy.com/v2/secret"
+    );
+    assert!(provider.request_headers().is_empty());
+    assert_eq!(provider.request_header_bytes(), 0);
+
+    provider.api_key = "secret".to_string();
+    provider.headers = Some(vec![
+        HeaderTemplate {
+            name: "Authorization".to_string(),
+            value: "Bearer {api_key}".to_string(),
+        },
+        HeaderTemplate {
+            name: "x-client".to_string(),
+            value: "iceth".to_string(),
+        },
+    ]);
+    assert_eq!(provider.url(), "https://eth-mainnet.g.alchemy.com/v2");
+    let headers = provider.request_headers();
+    assert_eq!(headers[0].name, "Authorization");
+    assert_eq!(headers[0].value, "Bearer secret");
+    assert_eq!(headers[1].value, "iceth");
+    assert_eq!(
+        provider.request_header_bytes(),
+        ("Authorization".len() + "Bearer secret".len() + "x-client".len() + "iceth".len()) as u64
+    );
+}
+
+#[test]
+fn check_provider_status() {
+    let mut provider = test_provider("https://cloudflare-eth.com", "/v1/mainnet");
+    assert_eq!(provider.status(100), ProviderStatus::Active);
+    provider.maintenance = Some(MaintenanceWindow {
+        start: 100,
+        end: 200,
+    });
+    assert_eq!(provider.status(99), ProviderStatus::Active);
+    assert_eq!(provider.status(100), ProviderStatus::Maintenance);
+    assert_eq!(provider.status(199), ProviderStatus::Maintenance);
+    assert_eq!(provider.status(200), ProviderStatus::Active);
+    provider.active = Some(false);
+    assert_eq!(provider.status(150), ProviderStatus::Disabled);
+    assert_eq!(provider.status(300), ProviderStatus::Disabled);
+
+    assert!(matches!(
+        set_provider_maintenance(
+            0,
+            Some(MaintenanceWindow {
+                start: 200,
+                end: 200
+            })
+        ),
+        Err(EthRpcError::InvalidProvider(_))
+    ));
+}
+
+#[test]
+fn check_provider_matches() {
+    let provider = Provider {
+        active: Some(false),
+        ..test_provider("https://cloudflare-eth.com", "/v1/mainnet")
+    };
+    let matches = |filter: ProviderFilter| provider_matches(&provider, &filter, 300);
+    assert!(matches(ProviderFilter::default()));
+    assert!(matches(ProviderFilter {
+        chain_id: Some(1),
+        host: Some("Cloudflare-ETH.com".to_string()),
+        active: Some(false),
+        ..ProviderFilter::default()
+    }));
+    assert!(!matches(ProviderFilter {
+        chain_id: Some(5),
+        ..ProviderFilter::default()
+    }));
+    assert!(!matches(ProviderFilter {
+        host: Some("mainnet.infura.io".to_string()),
+        ..ProviderFilter::default()
+    }));
+    assert!(!matches(ProviderFilter {
+        active: Some(true),
+        ..ProviderFilter::default()
+    }));
+}
+
+#[test]
+fn check_provider_page() {
+    // Providers 0..150 on chains 1 and 5 alternately.
+    PROVIDERS.with(|p| {
+        let mut p = p.borrow_mut();
+        for provider_id in 0..150 {
+            let provider = Provider {
+                provider_id,
+                chain_id: if provider_id % 2 == 0 { 1 } else { 5 },
+                ..test_provider("https://cloudflare-eth.com", "")
+            };
+            p.insert(provider_id, provider);
+        }
+    });
+    let ids = |page: &ProviderPage| -> Vec<u64> {
+        page.providers.iter().map(|p| p.provider_id).collect()
+    };
+    let chain_1 = ProviderFilter {
+        chain_id: Some(1),
+        ..ProviderFilter::default()
+    };
+
+    let page = provider_page(&chain_1, None, 3, 0);
+    assert_eq!(ids(&page), vec![0, 2, 4]);
+    assert_eq!(page.next_cursor, Some(6));
+    let page = provider_page(&chain_1, page.next_cursor, 3, 0);
+    assert_eq!(ids(&page), vec![6, 8, 10]);
+    assert_eq!(page.next_cursor, Some(12));
+
+    // A cursor at a deleted provider continues with the next matching provider.
+    PROVIDERS.with(|p| p.borrow_mut().remove(&12));
+    let page = provider_page(&chain_1, Some(12), 3, 0);
+    assert_eq!(ids(&page), vec![14, 16, 18]);
+
+    // The last page has no cursor, also when it is full.
+    let page = provider_page(&chain_1, Some(144), 3, 0);
+    assert_eq!(ids(&page), vec![144, 146, 148]);
+    assert_eq!(page.next_cursor, None);
+    let page = provider_page(&chain_1, Some(146), 3, 0);
+    assert_eq!(ids(&page), vec![146, 148]);
+    assert_eq!(page.next_cursor, None);
+    let page = provider_page(&chain_1, Some(149), 3, 0);
+    assert!(page.providers.is_empty());
+    assert_eq!(page.next_cursor, None);
+
+    // A limit of 0 or above the maximum returns the maximum page size, i.e. providers 0..=100
+    // without the deleted provider 12.
+    let all = ProviderFilter::default();
+    for limit in [0, GET_PROVIDERS_MAX_LIMIT + 1, u32::MAX] {
+        let page = provider_page(&all, None, limit, 0);
+        assert_eq!(page.providers.len(), GET_PROVIDERS_MAX_LIMIT as usize);
+        assert_eq!(page.next_cursor, Some(GET_PROVIDERS_MAX_LIMIT as u64 + 1));
+    }
+}
+
+#[test]
+fn check_rpc_access_allowed() {
+    let allowed = Principal::from_slice(&[1]);
+    let denied = Principal::from_slice(&[2]);
+    authorize(allowed, Auth::Rpc);
+
+    set_rpc_access(RpcAccess::Open);
+    assert!(rpc_access_allowed(allowed));
+    assert!(rpc_access_allowed(denied));
+
+    set_rpc_access(RpcAccess::Allowlist);
+    assert!(rpc_access_allowed(allowed));
+    assert!(!rpc_access_allowed(denied));
+
+    set_rpc_access(RpcAccess::Denylist);
+    assert!(rpc_access_allowed(allowed));
+    assert!(rpc_access_allowed(denied));
+    add_rpc_denylist(denied);
+    assert!(rpc_access_allowed(allowed));
+    assert!(!rpc_access_allowed(denied));
+    assert_eq!(get_rpc_denylist(), vec![denied]);
+    remove_rpc_denylist(denied);
+    assert!(rpc_access_allowed(denied));
+    assert!(get_rpc_denylist().is_empty());
 }